@@ -0,0 +1,615 @@
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::{keccak, native_token::LAMPORTS_PER_SOL, sysvar};
+
+use crate::{mul_div, BidErrorCode, Game, SafeMath, VaultLedger, VAULT_LEDGER_SEED};
+
+pub const AIRDROP_TICKET_SEED: &[u8] = b"airdrop_ticket";
+/// Entries the SlotHashes sysvar keeps, newest first
+pub const SLOT_HASHES_MAX_ENTRIES: usize = 512;
+
+/// Draw a purchase entered, seeded by [AIRDROP_TICKET_SEED, buyer].
+/// The purchase commits to randomness that is only revealed in a later slot,
+/// so the outcome can't be simulated or reverted by the buyer when it buys.
+#[account]
+#[derive(InitSpace)]
+pub struct AirdropTicket {
+    pub buyer: Pubkey,
+    pub game_id: u64,
+    // chance to win in basis points, zero when no draw is pending
+    pub odds: u64,
+    // amount won on a winning draw, a share of the pot when the purchase committed
+    pub prize: u64,
+    pub commit_slot: u64,
+    // oracle randomness account the draw committed to, None with slot hashes
+    pub randomness_account: Option<Pubkey>,
+}
+
+/// Source of the entropy used to settle an airdrop ticket
+pub trait RandomnessSource {
+    /// Value committed to by a ticket of `commit_slot`.
+    /// Fails while it isn't revealed, and returns None once it can't be revealed anymore.
+    fn reveal(&self, commit_slot: u64, clock: &Clock) -> Result<Option<[u8; 32]>>;
+}
+
+/// Hash of the first slot produced at or after `slot`, read from the SlotHashes sysvar data.
+/// None if the sysvar doesn't reach back to `slot` anymore or that slot isn't produced yet.
+pub fn slot_hash_at(data: &[u8], slot: u64) -> Option<[u8; 32]> {
+    // Layout: entry count (u64), then (slot: u64, hash: [u8; 32]) entries, newest first
+    let count = u64::from_le_bytes(data.get(..8)?.try_into().ok()?) as usize;
+
+    let mut found = None;
+    for index in 0..std::cmp::min(count, SLOT_HASHES_MAX_ENTRIES) {
+        let entry = data.get(8 + index * 40..8 + (index + 1) * 40)?;
+        let entry_slot = u64::from_le_bytes(entry[..8].try_into().ok()?);
+        if entry_slot < slot {
+            // An older entry proves no produced slot between it and `found` was missed
+            return found;
+        }
+
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(&entry[8..]);
+        found = Some(hash);
+    }
+
+    None
+}
+
+/// Default source, the hash of a slot `delay` slots after the purchase.
+/// The leader of that slot knows its hash first, so this is only suitable for small pots.
+pub struct SlotHashesRandomness<'a, 'info> {
+    pub slot_hashes: &'a AccountInfo<'info>,
+    pub delay: u64,
+}
+
+impl RandomnessSource for SlotHashesRandomness<'_, '_> {
+    fn reveal(&self, commit_slot: u64, clock: &Clock) -> Result<Option<[u8; 32]>> {
+        require_keys_eq!(
+            self.slot_hashes.key(),
+            sysvar::slot_hashes::ID,
+            BidErrorCode::InvalidRandomnessAccount
+        );
+
+        let reveal_slot = commit_slot.safe_add(std::cmp::max(self.delay, 1))?;
+        require!(
+            clock.slot > reveal_slot,
+            BidErrorCode::RandomnessNotRevealed
+        );
+
+        let data = self.slot_hashes.try_borrow_data()?;
+        Ok(slot_hash_at(&data, reveal_slot))
+    }
+}
+
+/// Data an oracle randomness account exposes after its 8 byte discriminator.
+/// `revealed_slot` stays zero until the value is revealed.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct RandomnessAccountData {
+    pub revealed_slot: u64,
+    pub value: [u8; 32],
+}
+
+/// Oracle-style source, an account owned by `oracle_program` revealing a value after the commit
+pub struct OracleRandomness<'a, 'info> {
+    pub account: &'a AccountInfo<'info>,
+    pub oracle_program: Pubkey,
+}
+
+impl OracleRandomness<'_, '_> {
+    fn load(&self) -> Result<RandomnessAccountData> {
+        require_keys_eq!(
+            *self.account.owner,
+            self.oracle_program,
+            BidErrorCode::InvalidRandomnessAccount
+        );
+
+        let data = self.account.try_borrow_data()?;
+        require!(data.len() >= 8, BidErrorCode::InvalidRandomnessAccount);
+        Ok(RandomnessAccountData::deserialize(&mut &data[8..])
+            .map_err(|_| BidErrorCode::InvalidRandomnessAccount)?)
+    }
+
+    /// Verifies a purchase commits to a value nobody knows yet
+    pub fn verify_unrevealed(&self) -> Result<()> {
+        require!(
+            self.load()?.revealed_slot == 0,
+            BidErrorCode::RandomnessAlreadyRevealed
+        );
+
+        Ok(())
+    }
+}
+
+impl RandomnessSource for OracleRandomness<'_, '_> {
+    fn reveal(&self, commit_slot: u64, _clock: &Clock) -> Result<Option<[u8; 32]>> {
+        let randomness = self.load()?;
+        require!(
+            randomness.revealed_slot > commit_slot,
+            BidErrorCode::RandomnessNotRevealed
+        );
+
+        Ok(Some(randomness.value))
+    }
+}
+
+/// Picks the oracle when one is configured, the slot hashes otherwise
+pub fn airdrop_randomness<'a, 'info>(
+    slot_hashes: &'a AccountInfo<'info>,
+    randomness_account: Option<&'a AccountInfo<'info>>,
+    oracle_program: Option<Pubkey>,
+    delay: u64,
+) -> Result<Box<dyn RandomnessSource + 'a>> {
+    match oracle_program {
+        Some(oracle_program) => {
+            let account = randomness_account.ok_or(BidErrorCode::InvalidRandomnessAccount)?;
+            Ok(Box::new(OracleRandomness {
+                account,
+                oracle_program,
+            }))
+        }
+        None => Ok(Box::new(SlotHashesRandomness { slot_hashes, delay })),
+    }
+}
+
+/// Chance to win in basis points, scaling with the purchase size and capped at `max_odds`.
+pub fn airdrop_odds(
+    purchase_amount: u64,
+    min_purchase: u64,
+    odds_per_sol: u64,
+    max_odds: u64,
+) -> Result<u64> {
+    if purchase_amount < min_purchase {
+        return Ok(0);
+    }
+
+    Ok(std::cmp::min(
+        mul_div(purchase_amount, odds_per_sol, LAMPORTS_PER_SOL)?,
+        max_odds,
+    ))
+}
+
+/// Enters a purchase in the draw, the ticket being passed as the first remaining account.
+/// The prize is fixed now, so the buyer can't wait for the pot to grow before settling.
+/// In oracle mode the randomness account must not be revealed yet.
+#[allow(clippy::too_many_arguments)]
+pub fn commit_airdrop_ticket<'info>(
+    ticket_info: &'info AccountInfo<'info>,
+    randomness_account: Option<&AccountInfo<'info>>,
+    oracle_program: Option<Pubkey>,
+    buyer: Pubkey,
+    game_id: u64,
+    odds: u64,
+    prize: u64,
+    clock: &Clock,
+) -> Result<()> {
+    let (ticket_address, _) =
+        Pubkey::find_program_address(&[AIRDROP_TICKET_SEED, buyer.as_ref()], &crate::ID);
+    require_keys_eq!(
+        ticket_info.key(),
+        ticket_address,
+        BidErrorCode::IncorrectAirdropTicket
+    );
+    require!(
+        ticket_info.is_writable,
+        BidErrorCode::IncorrectAirdropTicket
+    );
+
+    // Deserializing checks the program owns the ticket
+    let mut ticket = Account::<AirdropTicket>::try_from(ticket_info)?;
+    require!(ticket.odds == 0, BidErrorCode::AirdropTicketPending);
+
+    let randomness_account = match oracle_program {
+        Some(oracle_program) => {
+            let account = randomness_account.ok_or(BidErrorCode::InvalidRandomnessAccount)?;
+            OracleRandomness {
+                account,
+                oracle_program,
+            }
+            .verify_unrevealed()?;
+            Some(account.key())
+        }
+        None => None,
+    };
+
+    ticket.game_id = game_id;
+    ticket.odds = odds;
+    ticket.prize = prize;
+    ticket.commit_slot = clock.slot;
+    ticket.randomness_account = randomness_account;
+    ticket.exit(&crate::ID)
+}
+
+/// Roll of a ticket in [0, divider), mixing in the buyer so tickets sharing a value roll differently
+pub fn airdrop_roll(entropy: &[u8; 32], buyer: Pubkey, commit_slot: u64, divider: u64) -> u64 {
+    let hash = keccak::hashv(&[entropy, buyer.as_ref(), &commit_slot.to_le_bytes()]);
+    u64::from_le_bytes(hash.0[..8].try_into().unwrap()) % divider
+}
+
+/// Settles a pending ticket and returns the amount won from the pot, zero if it lost or lapsed.
+/// A ticket of another round than `game_id`, or not settled within `settle_window` slots
+/// of its purchase, lapses. The ticket is cleared either way so the buyer can enter the next draw.
+pub fn settle_airdrop_ticket(
+    ticket: &mut AirdropTicket,
+    source: &dyn RandomnessSource,
+    clock: &Clock,
+    game_id: u64,
+    pot: u64,
+    settle_window: u64,
+    divider: u64,
+) -> Result<u64> {
+    require!(ticket.odds > 0, BidErrorCode::NoAirdropTicket);
+
+    let lapsed =
+        ticket.game_id != game_id || clock.slot > ticket.commit_slot.safe_add(settle_window)?;
+    let prize = if lapsed {
+        0
+    } else {
+        match source.reveal(ticket.commit_slot, clock)? {
+            Some(entropy)
+                if airdrop_roll(&entropy, ticket.buyer, ticket.commit_slot, divider)
+                    < ticket.odds =>
+            {
+                // Earlier winners may have drawn the pot below the prize
+                std::cmp::min(ticket.prize, pot)
+            }
+            _ => 0,
+        }
+    };
+    ticket.odds = 0;
+    ticket.prize = 0;
+
+    Ok(prize)
+}
+
+#[derive(Accounts)]
+pub struct OpenAirdropTicket<'info> {
+    #[account(
+        init,
+        payer = buyer,
+        space = 8 + AirdropTicket::INIT_SPACE,
+        seeds = [AIRDROP_TICKET_SEED, buyer.key().as_ref()],
+        bump,
+    )]
+    pub airdrop_ticket: Account<'info, AirdropTicket>,
+
+    #[account(mut)]
+    pub buyer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SettleAirdrop<'info> {
+    #[account(mut, address = vault_ledger.game @ BidErrorCode::IncorrectGameId)]
+    pub game: Account<'info, Game>,
+
+    #[account(seeds = [VAULT_LEDGER_SEED], bump)]
+    pub vault_ledger: Account<'info, VaultLedger>,
+
+    /// CHECK: the chest vault recorded in the ledger, only lamports are moved out of it
+    #[account(mut, address = vault_ledger.chest_vault @ BidErrorCode::IncorrectChestVault)]
+    pub chest_vault: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [AIRDROP_TICKET_SEED, buyer.key().as_ref()],
+        bump,
+        has_one = buyer @ BidErrorCode::IncorrectAirdropTicket,
+    )]
+    pub airdrop_ticket: Account<'info, AirdropTicket>,
+
+    /// CHECK: the SlotHashes sysvar, verified by its address
+    #[account(address = sysvar::slot_hashes::ID)]
+    pub slot_hashes: UncheckedAccount<'info>,
+
+    /// CHECK: the oracle randomness account the ticket committed to, verified against the ticket
+    pub randomness_account: Option<UncheckedAccount<'info>>,
+
+    #[account(mut)]
+    pub buyer: Signer<'info>,
+}
+
+#[event]
+pub struct AirdropEvent {
+    pub game_id: u64,
+    pub winner: Pubkey,
+    pub amount: u64,
+    pub odds: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct AirdropTicketCommittedEvent {
+    pub game_id: u64,
+    pub buyer: Pubkey,
+    pub odds: u64,
+    pub commit_slot: u64,
+    pub timestamp: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stands in for an oracle or the slot hashes, revealing `value` from `revealed_slot` on
+    struct MockRandomness {
+        revealed_slot: u64,
+        value: Option<[u8; 32]>,
+    }
+
+    impl RandomnessSource for MockRandomness {
+        fn reveal(&self, commit_slot: u64, _clock: &Clock) -> Result<Option<[u8; 32]>> {
+            require!(
+                self.revealed_slot > commit_slot,
+                BidErrorCode::RandomnessNotRevealed
+            );
+            Ok(self.value)
+        }
+    }
+
+    fn ticket(odds: u64) -> AirdropTicket {
+        AirdropTicket {
+            buyer: Pubkey::new_unique(),
+            game_id: 1,
+            odds,
+            prize: 500,
+            commit_slot: 100,
+            randomness_account: None,
+        }
+    }
+
+    fn clock(slot: u64) -> Clock {
+        Clock {
+            slot,
+            ..Clock::default()
+        }
+    }
+
+    fn slot_hashes_data(entries: &[(u64, u8)]) -> Vec<u8> {
+        let mut data = (entries.len() as u64).to_le_bytes().to_vec();
+        for (slot, hash) in entries {
+            data.extend_from_slice(&slot.to_le_bytes());
+            data.extend_from_slice(&[*hash; 32]);
+        }
+        data
+    }
+
+    #[test]
+    fn unrevealed_ticket_stays_pending() {
+        let mut ticket = ticket(10_000);
+        let source = MockRandomness {
+            revealed_slot: 100,
+            value: Some([7; 32]),
+        };
+
+        assert!(
+            settle_airdrop_ticket(&mut ticket, &source, &clock(100), 1, 1_000, 512, 10_000)
+                .is_err()
+        );
+        assert_eq!(ticket.odds, 10_000);
+    }
+
+    #[test]
+    fn certain_ticket_wins_its_share_of_the_pot() {
+        let mut ticket = ticket(10_000);
+        let source = MockRandomness {
+            revealed_slot: 101,
+            value: Some([7; 32]),
+        };
+
+        let prize = settle_airdrop_ticket(&mut ticket, &source, &clock(105), 1, 1_000, 512, 10_000)
+            .unwrap();
+        assert_eq!(prize, 500);
+        assert_eq!(ticket.odds, 0);
+        // A settled ticket can't be settled twice
+        assert!(
+            settle_airdrop_ticket(&mut ticket, &source, &clock(105), 1, 1_000, 512, 10_000)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn roll_decides_against_the_odds() {
+        let mut ticket = ticket(1);
+        let value = [9; 32];
+        let roll = airdrop_roll(&value, ticket.buyer, ticket.commit_slot, 10_000);
+        ticket.odds = roll + 1;
+        let source = MockRandomness {
+            revealed_slot: 101,
+            value: Some(value),
+        };
+        assert_eq!(
+            settle_airdrop_ticket(&mut ticket, &source, &clock(105), 1, 1_000, 512, 10_000)
+                .unwrap(),
+            500
+        );
+
+        ticket.odds = std::cmp::max(roll, 1);
+        ticket.prize = 500;
+        let prize = settle_airdrop_ticket(&mut ticket, &source, &clock(105), 1, 1_000, 512, 10_000)
+            .unwrap();
+        assert_eq!(prize, if roll == 0 { 500 } else { 0 });
+    }
+
+    #[test]
+    fn lapsed_ticket_loses_and_is_cleared() {
+        let mut ticket = ticket(10_000);
+        let source = MockRandomness {
+            revealed_slot: 101,
+            value: None,
+        };
+
+        let prize =
+            settle_airdrop_ticket(&mut ticket, &source, &clock(1_000), 1, 1_000, 512, 10_000)
+                .unwrap();
+        assert_eq!(prize, 0);
+        assert_eq!(ticket.odds, 0);
+    }
+
+    #[test]
+    fn prize_is_the_one_fixed_at_commit() {
+        let source = MockRandomness {
+            revealed_slot: 101,
+            value: Some([7; 32]),
+        };
+
+        // the pot grew since the purchase
+        let mut grown = ticket(10_000);
+        assert_eq!(
+            settle_airdrop_ticket(&mut grown, &source, &clock(105), 1, 9_000, 512, 10_000).unwrap(),
+            500
+        );
+        assert_eq!(grown.prize, 0);
+
+        // earlier winners drew it down
+        let mut drawn = ticket(10_000);
+        assert_eq!(
+            settle_airdrop_ticket(&mut drawn, &source, &clock(105), 1, 300, 512, 10_000).unwrap(),
+            300
+        );
+    }
+
+    #[test]
+    fn ticket_of_another_round_or_past_the_window_lapses() {
+        // randomness revealing a winning value at any time
+        let source = MockRandomness {
+            revealed_slot: 101,
+            value: Some([7; 32]),
+        };
+
+        let mut other_round = ticket(10_000);
+        assert_eq!(
+            settle_airdrop_ticket(
+                &mut other_round,
+                &source,
+                &clock(105),
+                2,
+                1_000,
+                512,
+                10_000
+            )
+            .unwrap(),
+            0
+        );
+        assert_eq!(other_round.odds, 0);
+
+        let mut late = ticket(10_000);
+        assert_eq!(
+            settle_airdrop_ticket(&mut late, &source, &clock(613), 1, 1_000, 512, 10_000).unwrap(),
+            0
+        );
+        assert_eq!(late.odds, 0);
+
+        // a lapsed ticket doesn't wait for its randomness
+        let unrevealed = MockRandomness {
+            revealed_slot: u64::MAX,
+            value: None,
+        };
+        let mut never_revealed = ticket(10_000);
+        assert_eq!(
+            settle_airdrop_ticket(
+                &mut never_revealed,
+                &unrevealed,
+                &clock(613),
+                1,
+                1_000,
+                512,
+                10_000
+            )
+            .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn slot_hash_is_the_first_produced_slot_at_or_after_the_target() {
+        // slot 103 was skipped
+        let data = slot_hashes_data(&[(105, 5), (104, 4), (102, 2), (101, 1)]);
+
+        assert_eq!(slot_hash_at(&data, 104), Some([4; 32]));
+        assert_eq!(slot_hash_at(&data, 103), Some([4; 32]));
+        assert_eq!(slot_hash_at(&data, 102), Some([2; 32]));
+        // not produced yet
+        assert_eq!(slot_hash_at(&data, 106), None);
+        // older than what the sysvar still holds
+        assert_eq!(slot_hash_at(&data, 101), None);
+        assert_eq!(slot_hash_at(&data, 90), None);
+    }
+
+    #[test]
+    fn oracle_account_must_be_unrevealed_at_commit_and_revealed_after() {
+        let oracle_program = Pubkey::new_unique();
+        let key = Pubkey::new_unique();
+        let mut lamports = 0;
+        let mut data = vec![0u8; 8];
+        RandomnessAccountData {
+            revealed_slot: 0,
+            value: [0; 32],
+        }
+        .serialize(&mut data)
+        .unwrap();
+        let account = AccountInfo::new(
+            &key,
+            false,
+            false,
+            &mut lamports,
+            &mut data,
+            &oracle_program,
+            false,
+            0,
+        );
+        let oracle = OracleRandomness {
+            account: &account,
+            oracle_program,
+        };
+
+        assert!(oracle.verify_unrevealed().is_ok());
+        assert!(oracle.reveal(100, &clock(100)).is_err());
+
+        RandomnessAccountData {
+            revealed_slot: 101,
+            value: [3; 32],
+        }
+        .serialize(&mut &mut account.try_borrow_mut_data().unwrap()[8..])
+        .unwrap();
+        assert!(oracle.verify_unrevealed().is_err());
+        assert_eq!(oracle.reveal(100, &clock(102)).unwrap(), Some([3; 32]));
+        // a value revealed before the commit can't be used
+        assert!(oracle.reveal(101, &clock(102)).is_err());
+    }
+
+    #[test]
+    fn oracle_account_of_another_program_is_rejected() {
+        let key = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let mut lamports = 0;
+        let mut data = vec![0u8; 48];
+        let account = AccountInfo::new(
+            &key,
+            false,
+            false,
+            &mut lamports,
+            &mut data,
+            &owner,
+            false,
+            0,
+        );
+        let oracle = OracleRandomness {
+            account: &account,
+            oracle_program: Pubkey::new_unique(),
+        };
+
+        assert!(oracle.verify_unrevealed().is_err());
+    }
+
+    #[test]
+    fn airdrop_odds_are_capped_for_any_purchase() {
+        assert_eq!(airdrop_odds(u64::MAX, 100_000_000, 10, 500).unwrap(), 500);
+        assert_eq!(airdrop_odds(99_999_999, 100_000_000, 10, 500).unwrap(), 0);
+        assert_eq!(
+            airdrop_odds(LAMPORTS_PER_SOL, 100_000_000, 10, 500).unwrap(),
+            10
+        );
+        // odds per SOL that would overflow the odds are an error, not a wrap
+        assert!(airdrop_odds(u64::MAX, 0, u64::MAX, u64::MAX).is_err());
+    }
+}
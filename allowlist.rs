@@ -0,0 +1,110 @@
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::keccak;
+
+use crate::{Game, Ownership};
+
+/// Leaf of a wallet in the eligibility tree
+pub fn eligibility_leaf(wallet: &Pubkey) -> [u8; 32] {
+    keccak::hash(wallet.as_ref()).0
+}
+
+/// Verifies a proof built with sorted pairs, each node being keccak(min(a, b) || max(a, b))
+pub fn verify_merkle_proof(proof: &[[u8; 32]], root: [u8; 32], leaf: [u8; 32]) -> bool {
+    let mut node = leaf;
+    for sibling in proof.iter() {
+        node = if node <= *sibling {
+            keccak::hashv(&[&node, sibling]).0
+        } else {
+            keccak::hashv(&[sibling, &node]).0
+        };
+    }
+
+    node == root
+}
+
+#[derive(Accounts)]
+pub struct SetEligibilityRoot<'info> {
+    #[account(mut)]
+    pub game: Account<'info, Game>,
+
+    pub ownership: Account<'info, Ownership>,
+
+    pub owner: Signer<'info>,
+}
+
+#[event]
+pub struct EligibilityRootEvent {
+    pub game_id: u64,
+    pub root: Option<[u8; 32]>,
+    pub timestamp: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parent(a: [u8; 32], b: [u8; 32]) -> [u8; 32] {
+        if a <= b {
+            keccak::hashv(&[&a, &b]).0
+        } else {
+            keccak::hashv(&[&b, &a]).0
+        }
+    }
+
+    /// Leaves of four wallets and the root of their tree
+    fn tree() -> ([[u8; 32]; 4], [u8; 32]) {
+        let leaves = [0; 4].map(|_| eligibility_leaf(&Pubkey::new_unique()));
+        let root = parent(parent(leaves[0], leaves[1]), parent(leaves[2], leaves[3]));
+        (leaves, root)
+    }
+
+    #[test]
+    fn valid_proof_is_accepted() {
+        let (leaves, root) = tree();
+
+        let proof = [leaves[3], parent(leaves[0], leaves[1])];
+        assert!(verify_merkle_proof(&proof, root, leaves[2]));
+        let proof = [leaves[0], parent(leaves[2], leaves[3])];
+        assert!(verify_merkle_proof(&proof, root, leaves[1]));
+    }
+
+    #[test]
+    fn wrong_leaf_is_rejected() {
+        let (leaves, root) = tree();
+        let proof = [leaves[3], parent(leaves[0], leaves[1])];
+
+        assert!(!verify_merkle_proof(
+            &proof,
+            root,
+            eligibility_leaf(&Pubkey::new_unique())
+        ));
+        // a leaf of the tree with the proof of another one
+        assert!(!verify_merkle_proof(&proof, root, leaves[0]));
+    }
+
+    #[test]
+    fn single_leaf_tree_has_an_empty_proof() {
+        let leaf = eligibility_leaf(&Pubkey::new_unique());
+
+        assert!(verify_merkle_proof(&[], leaf, leaf));
+        assert!(!verify_merkle_proof(
+            &[],
+            leaf,
+            eligibility_leaf(&Pubkey::new_unique())
+        ));
+    }
+
+    #[test]
+    fn pairs_are_hashed_in_sorted_order() {
+        let low = [1; 32];
+        let high = [2; 32];
+        let root = keccak::hashv(&[&low, &high]).0;
+
+        // the same root whichever side the leaf is on
+        assert!(verify_merkle_proof(&[high], root, low));
+        assert!(verify_merkle_proof(&[low], root, high));
+        // a tree hashing the pair unsorted doesn't verify
+        let unsorted_root = keccak::hashv(&[&high, &low]).0;
+        assert!(!verify_merkle_proof(&[low], unsorted_root, high));
+    }
+}
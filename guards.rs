@@ -0,0 +1,59 @@
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::instruction::{get_stack_height, TRANSACTION_LEVEL_STACK_HEIGHT};
+use anchor_lang::solana_program::sysvar::{self, instructions};
+use anchor_lang::Discriminator;
+
+use crate::{instruction, BidErrorCode};
+
+fn is_buy_instruction(data: &[u8]) -> bool {
+    data.starts_with(instruction::BuyKeys::DISCRIMINATOR.as_ref())
+        || data.starts_with(instruction::BuyKeysWithReferralCode::DISCRIMINATOR.as_ref())
+        || data.starts_with(instruction::ReinvestRevenue::DISCRIMINATOR.as_ref())
+}
+
+/// Rejects purchases invoked through CPI by a program outside `approved_programs`,
+/// and transactions holding more than one buy instruction.
+/// Purchases invoked directly by an approved program are exempt.
+pub fn verify_human_purchase(
+    instructions_sysvar: &AccountInfo,
+    approved_programs: &[Pubkey],
+) -> Result<()> {
+    require_keys_eq!(
+        instructions_sysvar.key(),
+        sysvar::instructions::ID,
+        BidErrorCode::InvalidInstructionsSysvar
+    );
+
+    // Through CPI, the top level instruction being executed belongs to the calling program.
+    // Only a direct call is allowed, an approved program can't relay another program's purchase.
+    let stack_height = get_stack_height();
+    if stack_height > TRANSACTION_LEVEL_STACK_HEIGHT {
+        require!(
+            stack_height == TRANSACTION_LEVEL_STACK_HEIGHT + 1,
+            BidErrorCode::ProgramInvokedPurchase
+        );
+        let current_index = instructions::load_current_index_checked(instructions_sysvar)?;
+        let current_instruction =
+            instructions::load_instruction_at_checked(current_index as usize, instructions_sysvar)?;
+        require!(
+            approved_programs.contains(&current_instruction.program_id),
+            BidErrorCode::ProgramInvokedPurchase
+        );
+        return Ok(());
+    }
+
+    let mut buy_instructions = 0;
+    let mut index = 0;
+    while let Ok(instruction) = instructions::load_instruction_at_checked(index, instructions_sysvar) {
+        if instruction.program_id == crate::ID && is_buy_instruction(&instruction.data) {
+            buy_instructions += 1;
+        }
+        index += 1;
+    }
+    require!(
+        buy_instructions <= 1,
+        BidErrorCode::MultipleBuysInTransaction
+    );
+
+    Ok(())
+}
@@ -0,0 +1,544 @@
+use anchor_lang::prelude::*;
+use anchor_spl::token_2022::spl_token_2022::{
+    extension::{
+        immutable_owner::ImmutableOwner, transfer_hook::TransferHookAccount,
+        BaseStateWithExtensions, StateWithExtensions,
+    },
+    state::Account as Token2022Account,
+};
+use anchor_spl::token_interface::{self, Mint, TokenAccount, TokenInterface};
+use spl_tlv_account_resolution::{
+    account::ExtraAccountMeta, seeds::Seed, state::ExtraAccountMetaList,
+};
+use spl_transfer_hook_interface::instruction::ExecuteInstruction;
+
+use crate::{
+    BidErrorCode, Game, GroupRevenueCounter, Ownership, SafeMath, VaultLedger, VAULT_LEDGER_SEED,
+};
+
+pub const KEY_MINT_SEED: &[u8] = b"key_mint";
+pub const KEY_MINT_STATE_SEED: &[u8] = b"key_mint_state";
+pub const KEY_CHECKPOINT_SEED: &[u8] = b"key_checkpoint";
+/// Seed the transfer hook interface expects for the extra accounts of a mint
+pub const EXTRA_ACCOUNT_METAS_SEED: &[u8] = b"extra-account-metas";
+/// Mint state, source checkpoint and destination checkpoint
+pub const KEY_TOKEN_EXTRA_ACCOUNTS: usize = 3;
+/// Scale of the revenue accumulated per key token
+pub const KEY_REVENUE_PRECISION: u128 = 1_000_000_000_000;
+
+/// Key token of a round, seeded by [KEY_MINT_STATE_SEED, game_id].
+/// Also the mint authority of the key mint.
+#[account]
+#[derive(InitSpace)]
+pub struct KeyMintState {
+    pub game_id: u64,
+    pub mint: Pubkey,
+    // revenue earned by one key token since the round started, scaled by KEY_REVENUE_PRECISION
+    pub revenue_per_key: u128,
+    // key tokens minted by the program, burned tokens keep earning for their last holder
+    pub total_supply: u64,
+    pub bump: u8,
+}
+
+/// Revenue entitlement of a wallet in a round, seeded by [KEY_CHECKPOINT_SEED, mint, owner].
+/// Updated on every mint and transfer of the key token.
+#[account]
+#[derive(InitSpace)]
+pub struct KeyCheckpoint {
+    pub owner: Pubkey,
+    pub mint: Pubkey,
+    pub balance: u64,
+    pub revenue_per_key_paid: u128,
+    // revenue settled on the balance and not claimed yet
+    pub accrued: u64,
+}
+
+/// Credits the key holders share of a purchase to every key token minted so far.
+/// Returns the amount actually credited, the rounding leftover is for the caller to carry.
+pub fn credit_key_revenue(state: &mut KeyMintState, amount: u64) -> Result<u64> {
+    if state.total_supply == 0 {
+        return Ok(0);
+    }
+
+    let increment = (amount as u128)
+        .safe_mul(KEY_REVENUE_PRECISION)?
+        .checked_div(state.total_supply as u128)
+        .ok_or(BidErrorCode::MathOverflow)?;
+    state.revenue_per_key = state.revenue_per_key.safe_add(increment)?;
+
+    let distributed = increment.safe_mul(state.total_supply as u128)? / KEY_REVENUE_PRECISION;
+    Ok(u64::try_from(distributed).map_err(|_| BidErrorCode::MathOverflow)?)
+}
+
+/// Accrues the revenue of the checkpoint's balance up to the current accumulator
+pub fn settle_key_checkpoint(checkpoint: &mut KeyCheckpoint, state: &KeyMintState) -> Result<()> {
+    let earned = (checkpoint.balance as u128).safe_mul(
+        state
+            .revenue_per_key
+            .safe_sub(checkpoint.revenue_per_key_paid)?,
+    )? / KEY_REVENUE_PRECISION;
+    checkpoint.accrued = checkpoint
+        .accrued
+        .safe_add(u64::try_from(earned).map_err(|_| BidErrorCode::MathOverflow)?)?;
+    checkpoint.revenue_per_key_paid = state.revenue_per_key;
+
+    Ok(())
+}
+
+/// Moves the entitlement of `amount` key tokens from the source checkpoint to the destination one,
+/// settling both first. Without a destination the tokens stay with the source's owner.
+pub fn transfer_key_entitlement(
+    source: &mut KeyCheckpoint,
+    destination: Option<&mut KeyCheckpoint>,
+    state: &KeyMintState,
+    amount: u64,
+) -> Result<()> {
+    settle_key_checkpoint(source, state)?;
+
+    if let Some(destination) = destination {
+        settle_key_checkpoint(destination, state)?;
+        source.balance = source.balance.safe_sub(amount)?;
+        destination.balance = destination.balance.safe_add(amount)?;
+    }
+
+    Ok(())
+}
+
+/// Settles the checkpoint and pays its accrued revenue up to `available`.
+/// Returns what was owed and what is paid, the rest stays accrued.
+pub fn claim_key_checkpoint(
+    checkpoint: &mut KeyCheckpoint,
+    state: &KeyMintState,
+    available: u64,
+) -> Result<(u64, u64)> {
+    settle_key_checkpoint(checkpoint, state)?;
+    require!(checkpoint.accrued > 0, BidErrorCode::NoRevenue);
+
+    let owed = checkpoint.accrued;
+    let paid = std::cmp::min(owed, available);
+    checkpoint.accrued = owed.safe_sub(paid)?;
+
+    Ok((owed, paid))
+}
+
+/// Mints purchased keys to the buyer and checkpoints its revenue entitlement
+pub fn mint_key_tokens<'info>(
+    state: &mut Account<'info, KeyMintState>,
+    mint: &InterfaceAccount<'info, Mint>,
+    to: &InterfaceAccount<'info, TokenAccount>,
+    checkpoint: &mut Account<'info, KeyCheckpoint>,
+    token_program: &Interface<'info, TokenInterface>,
+    game_id: u64,
+    keys: u64,
+) -> Result<()> {
+    require!(state.game_id == game_id, BidErrorCode::IncorrectGameId);
+    require_keys_eq!(state.mint, mint.key(), BidErrorCode::IncorrectKeyMint);
+    require_keys_eq!(to.mint, mint.key(), BidErrorCode::IncorrectKeyMint);
+    assert_immutable_owner(&to.to_account_info())?;
+    require_keys_eq!(
+        checkpoint.owner,
+        to.owner,
+        BidErrorCode::IncorrectKeyCheckpoint
+    );
+    require_keys_eq!(
+        checkpoint.mint,
+        mint.key(),
+        BidErrorCode::IncorrectKeyCheckpoint
+    );
+
+    settle_key_checkpoint(checkpoint, state)?;
+    checkpoint.balance = checkpoint.balance.safe_add(keys)?;
+    state.total_supply = state.total_supply.safe_add(keys)?;
+
+    let game_id_bytes = state.game_id.to_le_bytes();
+    let signer_seeds: &[&[&[u8]]] = &[&[KEY_MINT_STATE_SEED, &game_id_bytes, &[state.bump]]];
+    token_interface::mint_to(
+        CpiContext::new_with_signer(
+            token_program.to_account_info(),
+            token_interface::MintTo {
+                mint: mint.to_account_info(),
+                to: to.to_account_info(),
+                authority: state.to_account_info(),
+            },
+            signer_seeds,
+        ),
+        keys,
+    )
+}
+
+/// Writes the accounts Token-2022 appends to a transfer of the key token: the mint state,
+/// then the checkpoints of the source and destination token account owners
+pub fn init_key_token_extra_account_metas(
+    extra_account_meta_list: &AccountInfo,
+    key_mint_state: Pubkey,
+) -> Result<()> {
+    // Execute accounts: source, mint, destination, authority, extra account metas
+    let extra_account_metas = [
+        ExtraAccountMeta::new_with_pubkey(&key_mint_state, false, false)?,
+        ExtraAccountMeta::new_with_seeds(
+            &[
+                Seed::Literal {
+                    bytes: KEY_CHECKPOINT_SEED.to_vec(),
+                },
+                Seed::AccountKey { index: 1 },
+                Seed::AccountData {
+                    account_index: 0,
+                    data_index: 32,
+                    length: 32,
+                },
+            ],
+            false,
+            true,
+        )?,
+        ExtraAccountMeta::new_with_seeds(
+            &[
+                Seed::Literal {
+                    bytes: KEY_CHECKPOINT_SEED.to_vec(),
+                },
+                Seed::AccountKey { index: 1 },
+                Seed::AccountData {
+                    account_index: 2,
+                    data_index: 32,
+                    length: 32,
+                },
+            ],
+            false,
+            true,
+        )?,
+    ];
+
+    ExtraAccountMetaList::init::<ExecuteInstruction>(
+        &mut extra_account_meta_list.try_borrow_mut_data()?,
+        &extra_account_metas,
+    )?;
+
+    Ok(())
+}
+
+/// Rejects calls to the hook that don't come from a Token-2022 transfer
+pub fn assert_key_token_transferring(source_token: &AccountInfo) -> Result<()> {
+    let data = source_token.try_borrow_data()?;
+    let account = StateWithExtensions::<Token2022Account>::unpack(&data)?;
+    let extension = account.get_extension::<TransferHookAccount>()?;
+    require!(
+        bool::from(extension.transferring),
+        BidErrorCode::NotTransferringKeys
+    );
+
+    Ok(())
+}
+
+/// Rejects key token accounts whose owner can be reassigned. Checkpoints follow the owner
+/// of a token account, so its owner must not change while it holds key tokens.
+pub fn assert_immutable_owner(token_account: &AccountInfo) -> Result<()> {
+    let data = token_account.try_borrow_data()?;
+    let account = StateWithExtensions::<Token2022Account>::unpack(&data)?;
+    account
+        .get_extension::<ImmutableOwner>()
+        .map_err(|_| BidErrorCode::MutableKeyTokenOwner)?;
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct CreateKeyMint<'info> {
+    pub game: Account<'info, Game>,
+
+    pub ownership: Account<'info, Ownership>,
+
+    #[account(
+        init,
+        payer = owner,
+        space = 8 + KeyMintState::INIT_SPACE,
+        seeds = [KEY_MINT_STATE_SEED, &game.game_id.to_le_bytes()],
+        bump,
+    )]
+    pub key_mint_state: Account<'info, KeyMintState>,
+
+    #[account(
+        init,
+        payer = owner,
+        seeds = [KEY_MINT_SEED, &game.game_id.to_le_bytes()],
+        bump,
+        mint::decimals = 0,
+        mint::authority = key_mint_state,
+        mint::token_program = token_program,
+        extensions::transfer_hook::authority = key_mint_state,
+        extensions::transfer_hook::program_id = crate::ID,
+    )]
+    pub key_mint: InterfaceAccount<'info, Mint>,
+
+    /// CHECK: initialized with the extra account metas of the transfer hook
+    #[account(
+        init,
+        payer = owner,
+        space = ExtraAccountMetaList::size_of(KEY_TOKEN_EXTRA_ACCOUNTS)?,
+        seeds = [EXTRA_ACCOUNT_METAS_SEED, key_mint.key().as_ref()],
+        bump,
+    )]
+    pub extra_account_meta_list: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitKeyCheckpoint<'info> {
+    pub key_mint: InterfaceAccount<'info, Mint>,
+
+    /// CHECK: any wallet can receive key tokens, only its key is used
+    pub checkpoint_owner: UncheckedAccount<'info>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + KeyCheckpoint::INIT_SPACE,
+        seeds = [KEY_CHECKPOINT_SEED, key_mint.key().as_ref(), checkpoint_owner.key().as_ref()],
+        bump,
+    )]
+    pub key_checkpoint: Account<'info, KeyCheckpoint>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct TransferHook<'info> {
+    #[account(token::mint = mint)]
+    pub source_token: InterfaceAccount<'info, TokenAccount>,
+
+    pub mint: InterfaceAccount<'info, Mint>,
+
+    #[account(token::mint = mint)]
+    pub destination_token: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: owner or delegate of the source, verified by Token-2022
+    pub authority: UncheckedAccount<'info>,
+
+    /// CHECK: extra account metas of the mint
+    #[account(seeds = [EXTRA_ACCOUNT_METAS_SEED, mint.key().as_ref()], bump)]
+    pub extra_account_meta_list: UncheckedAccount<'info>,
+
+    pub key_mint_state: Account<'info, KeyMintState>,
+
+    #[account(
+        mut,
+        seeds = [KEY_CHECKPOINT_SEED, mint.key().as_ref(), source_token.owner.as_ref()],
+        bump,
+    )]
+    pub source_checkpoint: Account<'info, KeyCheckpoint>,
+
+    /// CHECK: same account as the source checkpoint on a transfer between accounts of one wallet,
+    /// deserialized and verified by its seeds otherwise
+    #[account(
+        mut,
+        seeds = [KEY_CHECKPOINT_SEED, mint.key().as_ref(), destination_token.owner.as_ref()],
+        bump,
+    )]
+    pub destination_checkpoint: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(game_id: u64)]
+pub struct ClaimKeyRevenue<'info> {
+    #[account(mut, address = vault_ledger.game @ BidErrorCode::IncorrectGameId)]
+    pub game: Account<'info, Game>,
+
+    #[account(seeds = [KEY_MINT_STATE_SEED, &game_id.to_le_bytes()], bump = key_mint_state.bump)]
+    pub key_mint_state: Account<'info, KeyMintState>,
+
+    #[account(
+        mut,
+        seeds = [KEY_CHECKPOINT_SEED, key_mint_state.mint.as_ref(), owner.key().as_ref()],
+        bump,
+    )]
+    pub key_checkpoint: Account<'info, KeyCheckpoint>,
+
+    // counter of the claimed round, holding its unclaimed revenue once the round is finished
+    #[account(mut)]
+    pub group_revenue_counter: Account<'info, GroupRevenueCounter>,
+
+    #[account(mut, seeds = [VAULT_LEDGER_SEED], bump)]
+    pub vault_ledger: Account<'info, VaultLedger>,
+
+    /// CHECK: the chest vault recorded in the ledger, only lamports are moved out of it
+    #[account(mut, address = vault_ledger.chest_vault @ BidErrorCode::IncorrectChestVault)]
+    pub chest_vault: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+}
+
+#[event]
+pub struct KeyMintCreatedEvent {
+    pub game_id: u64,
+    pub mint: Pubkey,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct KeyRevenueClaimedEvent {
+    pub game_id: u64,
+    pub owner: Pubkey,
+    pub amount: u64,
+    pub timestamp: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anchor_lang::solana_program::program_pack::Pack;
+    use anchor_spl::token_2022::spl_token_2022::{
+        extension::{ExtensionType, StateWithExtensionsMut},
+        state::AccountState,
+    };
+
+    const SOL: u64 = 1_000_000_000;
+
+    fn key_mint_state() -> KeyMintState {
+        KeyMintState {
+            game_id: 1,
+            mint: Pubkey::new_unique(),
+            revenue_per_key: 0,
+            total_supply: 0,
+            bump: 255,
+        }
+    }
+
+    fn checkpoint(state: &KeyMintState) -> KeyCheckpoint {
+        KeyCheckpoint {
+            owner: Pubkey::new_unique(),
+            mint: state.mint,
+            balance: 0,
+            revenue_per_key_paid: 0,
+            accrued: 0,
+        }
+    }
+
+    fn mint(state: &mut KeyMintState, checkpoint: &mut KeyCheckpoint, keys: u64) {
+        settle_key_checkpoint(checkpoint, state).unwrap();
+        checkpoint.balance += keys;
+        state.total_supply += keys;
+    }
+
+    #[test]
+    fn revenue_follows_the_transferred_keys() {
+        let mut state = key_mint_state();
+        let mut alice = checkpoint(&state);
+        let mut bob = checkpoint(&state);
+
+        mint(&mut state, &mut alice, 10);
+        assert_eq!(credit_key_revenue(&mut state, SOL).unwrap(), SOL);
+
+        // the revenue earned before the transfer stays with the sender
+        transfer_key_entitlement(&mut alice, Some(&mut bob), &state, 4).unwrap();
+        assert_eq!((alice.balance, alice.accrued), (6, SOL));
+        assert_eq!((bob.balance, bob.accrued), (4, 0));
+
+        credit_key_revenue(&mut state, SOL).unwrap();
+        settle_key_checkpoint(&mut alice, &state).unwrap();
+        settle_key_checkpoint(&mut bob, &state).unwrap();
+        assert_eq!(alice.accrued, SOL + SOL * 6 / 10);
+        assert_eq!(bob.accrued, SOL * 4 / 10);
+    }
+
+    #[test]
+    fn transfer_within_a_wallet_keeps_the_entitlement() {
+        let mut state = key_mint_state();
+        let mut alice = checkpoint(&state);
+
+        mint(&mut state, &mut alice, 10);
+        credit_key_revenue(&mut state, SOL).unwrap();
+
+        transfer_key_entitlement(&mut alice, None, &state, 4).unwrap();
+        assert_eq!((alice.balance, alice.accrued), (10, SOL));
+    }
+
+    #[test]
+    fn transfer_above_the_balance_is_an_error() {
+        let mut state = key_mint_state();
+        let mut alice = checkpoint(&state);
+        let mut bob = checkpoint(&state);
+
+        mint(&mut state, &mut alice, 1);
+        assert!(transfer_key_entitlement(&mut alice, Some(&mut bob), &state, 2).is_err());
+    }
+
+    #[test]
+    fn claim_pays_what_the_vault_can_spare() {
+        let mut state = key_mint_state();
+        let mut alice = checkpoint(&state);
+
+        mint(&mut state, &mut alice, 3);
+        // a third of a lamport per key is dust
+        assert_eq!(credit_key_revenue(&mut state, SOL + 1).unwrap(), SOL);
+
+        assert_eq!(
+            claim_key_checkpoint(&mut alice, &state, SOL / 2).unwrap(),
+            (SOL, SOL / 2)
+        );
+        assert_eq!(alice.accrued, SOL / 2);
+
+        assert_eq!(
+            claim_key_checkpoint(&mut alice, &state, u64::MAX).unwrap(),
+            (SOL / 2, SOL / 2)
+        );
+        assert!(claim_key_checkpoint(&mut alice, &state, u64::MAX).is_err());
+    }
+
+    fn token_account_data(immutable_owner: bool) -> Vec<u8> {
+        let base = Token2022Account {
+            mint: Pubkey::new_unique(),
+            owner: Pubkey::new_unique(),
+            amount: 1,
+            state: AccountState::Initialized,
+            ..Default::default()
+        };
+
+        if !immutable_owner {
+            let mut data = vec![0; Token2022Account::LEN];
+            Token2022Account::pack(base, &mut data).unwrap();
+            return data;
+        }
+
+        let len = ExtensionType::try_calculate_account_len::<Token2022Account>(&[
+            ExtensionType::ImmutableOwner,
+        ])
+        .unwrap();
+        let mut data = vec![0; len];
+        let mut account =
+            StateWithExtensionsMut::<Token2022Account>::unpack_uninitialized(&mut data).unwrap();
+        account.init_extension::<ImmutableOwner>(true).unwrap();
+        account.base = base;
+        account.pack_base();
+        account.init_account_type().unwrap();
+        data
+    }
+
+    #[test]
+    fn key_token_account_owner_must_be_immutable() {
+        let key = Pubkey::new_unique();
+        let token_program = anchor_spl::token_2022::ID;
+
+        for (immutable_owner, accepted) in [(true, true), (false, false)] {
+            let mut lamports = 0;
+            let mut data = token_account_data(immutable_owner);
+            let account = AccountInfo::new(
+                &key,
+                false,
+                false,
+                &mut lamports,
+                &mut data,
+                &token_program,
+                false,
+                0,
+            );
+            assert_eq!(assert_immutable_owner(&account).is_ok(), accepted);
+        }
+    }
+}
@@ -1,8 +1,29 @@
 use anchor_lang::{prelude::*, system_program};
-use anchor_spl::token;
 
+pub mod airdrop;
+pub mod allowlist;
+pub mod guards;
+pub mod key_token;
+pub mod math;
+pub mod prize_stream;
+pub mod purchase;
+pub mod referral;
+pub mod revenue;
+pub mod teams;
 pub mod utils;
+pub mod vault;
+pub use airdrop::*;
+pub use allowlist::*;
+pub use guards::*;
+pub use key_token::*;
+pub use math::*;
+pub use prize_stream::*;
+pub use purchase::*;
+pub use referral::*;
+pub use revenue::*;
+pub use teams::*;
 pub use utils::*;
+pub use vault::*;
 
 declare_id!("77iKeKrz9xHSzyPHqP3haspcigK8kQARBk6NsFnWmp9j");
 
@@ -41,6 +62,82 @@ pub mod solana_lastbid_program {
     const LAST_BIDDER_SHARE: u64 = 6000; // 60%
     const KEY_HOLDERS_SHARE: u64 = 4000; // 40%
     const REFERRAL_SHARE: u64 = 2000; // 20%
+    const SECOND_TIER_REFERRAL_SHARE: u64 = 2500; // 25% of the direct referral bonus, taken from the treasury fee
+    const THIRD_TIER_REFERRAL_SHARE: u64 = 0; // 0: disabled
+    const REFERRAL_VOLUME_TIERS: [ReferralVolumeTier; 3] = [
+        ReferralVolumeTier { min_volume: 0, share: REFERRAL_SHARE },
+        ReferralVolumeTier { min_volume: 100_000_000_000, share: 2500 }, // 25% from 100 SOL referred
+        ReferralVolumeTier { min_volume: 500_000_000_000, share: 3000 }, // 30% from 500 SOL referred
+    ];
+    const REFERRAL_REBATE_SHARE: u64 = 1000; // 10% of the rebate source given back to the buyer
+    const REFERRAL_REBATE_SOURCE: RebateSource = RebateSource::TreasuryFee;
+    const STICKY_REFERRAL: StickyReferral = StickyReferral::Off; // Round: bound for the round, Permanent: bound for good
+
+    // prize pool / key holders / next round of each purchase, then key holders / next round of the main prize
+    const TEAMS: [Team; TEAM_COUNT] = [
+        Team {
+            prize_pool_share: LAST_BIDDER_SHARE,
+            key_holders_share: KEY_HOLDERS_SHARE,
+            next_round_share: 0,
+            final_key_holders_share: 0,
+            final_next_round_share: 0,
+        },
+        Team {
+            prize_pool_share: 5000,
+            key_holders_share: 4500,
+            next_round_share: 500,
+            final_key_holders_share: 1500,
+            final_next_round_share: 500,
+        },
+        Team {
+            prize_pool_share: 4000,
+            key_holders_share: 5000,
+            next_round_share: 1000,
+            final_key_holders_share: 3000,
+            final_next_round_share: 1000,
+        },
+        Team {
+            prize_pool_share: 7000,
+            key_holders_share: 2000,
+            next_round_share: 1000,
+            final_key_holders_share: 0,
+            final_next_round_share: 3000,
+        },
+    ];
+
+    const NEXT_ROUND_SHARE: u64 = 0; // taken from the prize pool share of every purchase to seed the next round, 0: disabled
+    const PURCHASE_TEAMS: [Team; TEAM_COUNT] = purchase_teams(TEAMS, NEXT_ROUND_SHARE); // split of every purchase, checked at compile time
+
+    const AIRDROP_SHARE: u64 = 100; // 1% of the prize pool amount
+    const AIRDROP_MIN_PURCHASE: u64 = 100_000_000; // 0.1 SOL
+    const AIRDROP_ODDS_PER_SOL: u64 = 100; // 1% chance per SOL spent
+    const AIRDROP_MAX_ODDS: u64 = 7500; // 75%
+    const AIRDROP_PAYOUT_SHARE: u64 = 5000; // 50% of the airdrop pot
+    const AIRDROP_ORACLE: Option<Pubkey> = None; // None: slot hashes
+    const AIRDROP_REVEAL_DELAY: u64 = 2; // slots between a purchase and the slot hash settling its draw
+    const AIRDROP_SETTLE_WINDOW: u64 = 512; // slots after a purchase its draw can be settled in, as far back as slot hashes go
+
+    const MAX_KEYS_PER_TX: u64 = 0; // 0: no cap
+    const MAX_KEYS_PER_ROUND_PER_WALLET: u64 = 0; // 0: no cap
+    const PURCHASE_COOLDOWN: i64 = 0; // seconds between purchases of a wallet, 0: disabled
+    const PURCHASE_COOLDOWN_SLOTS: u64 = 0; // slots between purchases of a wallet, 0: disabled
+    // Tie-break: the first purchase processed in a slot becomes the last bidder,
+    // any later purchase landing in the same slot is rejected
+    const ONE_PURCHASE_PER_SLOT: bool = false;
+    const HUMANS_ONLY: bool = false; // reject purchases from unknown programs and several buys in one transaction
+    const APPROVED_INTEGRATORS: &[Pubkey] = &[]; // programs allowed to buy through CPI in HUMANS_ONLY mode
+    const PURCHASE_LIMITS: PurchaseLimits = PurchaseLimits {
+        max_keys_per_tx: MAX_KEYS_PER_TX,
+        max_keys_per_round_per_wallet: MAX_KEYS_PER_ROUND_PER_WALLET,
+        cooldown: PURCHASE_COOLDOWN,
+        cooldown_slots: PURCHASE_COOLDOWN_SLOTS,
+        one_purchase_per_slot: ONE_PURCHASE_PER_SLOT,
+    };
+
+    const REVENUE_WEIGHTING: RevenueWeighting = RevenueWeighting::Flat; // Decay: earlier keys earn more of later purchases
+    const PRIZE_VESTING_PERIOD: i64 = 0; // seconds the main prize vests over, 0: paid at once
+    const KEY_TOKEN_MODE: bool = false; // keys minted as a Token-2022 token per round, revenue follows the balances evenly
+    const REVENUE_CLAIM_WINDOW: i64 = 0; // seconds to claim a round's revenue once the next one starts, 0: no expiry
 
     const DIVIDER: u64 = 10000;
 
@@ -82,8 +179,24 @@ pub mod solana_lastbid_program {
         Ok(())
     }
 
+    pub fn initialize_vault_ledger(ctx: Context<InitializeVaultLedger>) -> Result<()> {
+        let ownership = &ctx.accounts.ownership;
+        ownership.verify_ownership(ctx.accounts.owner.key())?;
+
+        // The game and chest vault are recorded by the first initialize_game
+        let vault_ledger = &mut ctx.accounts.vault_ledger;
+        vault_ledger.referral_reserved = 0;
+        vault_ledger.past_revenue_reserved = 0;
+        vault_ledger.prize_stream_reserved = 0;
+        vault_ledger.game = Pubkey::default();
+        vault_ledger.chest_vault = Pubkey::default();
+
+        Ok(())
+    }
+
     pub fn create_key_holder_account(
         ctx: Context<CreateKeyHolderAccount>,
+        game_id: u64,
         group_number: u64,
     ) -> Result<()> {
         let ownership = &mut ctx.accounts.ownership;
@@ -91,15 +204,29 @@ pub mod solana_lastbid_program {
 
         ownership.verify_ownership(ctx.accounts.owner.key())?;
 
+        key_holders.game_id = game_id;
         key_holders.group_number = group_number;
         key_holders.holders = vec![];
 
         Ok(())
     }
 
-    pub fn close_key_holder_account(ctx: Context<CloseKeyHolderAccount>, _group_number: u64) -> Result<()> {
+    pub fn close_key_holder_account(ctx: Context<CloseKeyHolders>, _group_number: u64) -> Result<()> {
         let ownership = &ctx.accounts.ownership;
         ownership.verify_ownership(ctx.accounts.owner.key())?;
+
+        // Verify no holder of the group can still claim revenue from it
+        require!(
+            key_holders_closable(
+                &ctx.accounts.key_holders,
+                &ctx.accounts.group_revenue_counter,
+                ctx.accounts.game.game_id,
+                REVENUE_CLAIM_WINDOW,
+                Clock::get()?.unix_timestamp,
+            )?,
+            BidErrorCode::RevenueStillClaimable
+        );
+
         Ok(())
     }
 
@@ -107,51 +234,109 @@ pub mod solana_lastbid_program {
         ctx: Context<InitializeGame>,
         bid_token_mint: Pubkey,
         treasury_wallet: Pubkey,
+        eligibility_root: Option<[u8; 32]>,
     ) -> Result<()> {
         let game: &mut Account<'_, Game> = &mut ctx.accounts.game;
         let ownership = &ctx.accounts.ownership;
         let group_revenue_counter = &mut ctx.accounts.group_revenue_counter;
+        let vault_ledger = &mut ctx.accounts.vault_ledger;
         let clock = Clock::get()?;
 
         // Verify the ownership
         ownership.verify_ownership(ctx.accounts.payer.key())?;
 
+        // Bind the vault ledger to the game and chest vault, they are verified by their seeds here
+        bind_vault_ledger(
+            vault_ledger,
+            game.key(),
+            ctx.accounts.chest_vault.key(),
+        )?;
+
         // The game can be initialized 10(REST_TIME) hours later after the last game ended
         require!(
-            game.timer_end == 0 || game.timer_end + REST_TIME < clock.unix_timestamp,
+            game.timer_end == 0 || game.timer_end.safe_add(REST_TIME)? < clock.unix_timestamp,
             BidErrorCode::RestNotFinished
         );
 
-        system_program::transfer(
-            CpiContext::new(
-                ctx.accounts.system_program.to_account_info(),
-                system_program::Transfer {
-                    from: ctx.accounts.payer.to_account_info().clone(),
-                    to: ctx.accounts.chest_vault.to_account_info().clone(),
-                },
-            ),
-            INITIAL_PRIZE_POOL,
-        )?;
+        // The prize pool of the last game has to be released before a new one replaces it
+        require!(
+            game.timer_end == 0 || game.prized,
+            BidErrorCode::PrizeNotReleased
+        );
+
+        // The seed set aside by the previous round funds the prize pool first,
+        // the owner only tops it up to INITIAL_PRIZE_POOL
+        let seed = game.next_round_seed.safe_add(game.dust_carry)?;
+        let owner_top_up = if seed < INITIAL_PRIZE_POOL {
+            INITIAL_PRIZE_POOL.safe_sub(seed)?
+        } else {
+            0
+        };
+        if owner_top_up > 0 {
+            system_program::transfer(
+                CpiContext::new(
+                    ctx.accounts.system_program.to_account_info(),
+                    system_program::Transfer {
+                        from: ctx.accounts.payer.to_account_info().clone(),
+                        to: ctx.accounts.chest_vault.to_account_info().clone(),
+                    },
+                ),
+                owner_top_up,
+            )?;
+        }
+
+        // Unclaimed revenue of the previous round stays claimable, reserved in the vault ledger
+        if let Some(previous_group_revenue_counter) =
+            ctx.accounts.previous_group_revenue_counter.as_mut()
+        {
+            require!(
+                previous_group_revenue_counter.game_id == game.game_id,
+                BidErrorCode::IncorrectGameId
+            );
+            previous_group_revenue_counter.outstanding = game.revenue_earned;
+            previous_group_revenue_counter.closed_at = clock.unix_timestamp;
+            vault_ledger.past_revenue_reserved = vault_ledger
+                .past_revenue_reserved
+                .safe_add(game.revenue_earned)?;
+        } else {
+            require!(
+                game.revenue_earned == 0,
+                BidErrorCode::MissingPreviousRevenueCounter
+            );
+        }
 
-        game.game_id = game.game_id + 1;
+        game.game_id = game.game_id.safe_add(1)?;
         game.owner = ctx.accounts.payer.key();
         game.last_bidder = Pubkey::default();
         game.treasury = treasury_wallet;
         game.bid_token_mint = bid_token_mint;
         game.current_price = INITIAL_KEY_PRICE;
-        game.prize_pool_balance = INITIAL_PRIZE_POOL + game.revenue_earned;
+        game.prize_pool_balance = seed.safe_add(owner_top_up)?;
         game.revenue_earned = 0;
+        game.next_round_seed = 0;
+        game.dust_carry = 0;
+        game.referral_earned = 0;
+        game.referral_tier_earned = [0; REFERRAL_TIERS];
         game.last_purchase_time = 0;
-        game.timer_end = clock.unix_timestamp + BASE_TIMER;
+        game.last_purchase_slot = 0;
+        game.timer_end = clock.unix_timestamp.safe_add(BASE_TIMER)?;
         game.total_keys = 0;
+        game.total_weight = 0;
         game.total_amount = 0;
         game.total_groups = 0;
         game.total_holders = 0;
+        game.team_keys = [0; TEAM_COUNT];
+        game.team_amounts = [0; TEAM_COUNT];
+        game.last_bidder_team = 0;
+        game.eligibility_root = eligibility_root;
         game.active = true;
         game.prized = false;
         // game.last_chainlink_timestamp = get_chainlink_timestamp(&ctx.accounts.chainlink_feed)?;
 
+        group_revenue_counter.game_id = game.game_id;
         group_revenue_counter.group_counter = vec![];
+        group_revenue_counter.outstanding = 0;
+        group_revenue_counter.closed_at = 0;
 
         emit!(GameInitEvent {
             owner: game.owner,
@@ -159,6 +344,42 @@ pub mod solana_lastbid_program {
             timer_end: game.timer_end,
         });
 
+        emit!(RoundSeededEvent {
+            game_id: game.game_id,
+            seed,
+            owner_top_up,
+            prize_pool: game.prize_pool_balance,
+            timestamp: clock.unix_timestamp,
+        });
+
+        assert_vault_solvent(
+            &ctx.accounts.chest_vault.to_account_info(),
+            Some(&ctx.accounts.game),
+            &ctx.accounts.vault_ledger,
+        )?;
+
+        Ok(())
+    }
+
+    pub fn set_eligibility_root(
+        ctx: Context<SetEligibilityRoot>,
+        eligibility_root: Option<[u8; 32]>,
+    ) -> Result<()> {
+        let game = &mut ctx.accounts.game;
+        let ownership = &ctx.accounts.ownership;
+        let clock = Clock::get()?;
+
+        ownership.verify_ownership(ctx.accounts.owner.key())?;
+
+        // Wallets that already proved their eligibility in this round stay eligible
+        game.eligibility_root = eligibility_root;
+
+        emit!(EligibilityRootEvent {
+            game_id: game.game_id,
+            root: game.eligibility_root,
+            timestamp: clock.unix_timestamp,
+        });
+
         Ok(())
     }
 
@@ -168,11 +389,13 @@ pub mod solana_lastbid_program {
         Ok(())
     }
 
-    pub fn buy_keys(
-        ctx: Context<BuyKeys>,
+    pub fn buy_keys<'info>(
+        ctx: Context<'_, '_, 'info, 'info, BuyKeys<'info>>,
         group_number: u64,
         suggested_amount: u64,
         number_of_keys: u64,
+        team: u8,
+        proof: Vec<[u8; 32]>,
     ) -> Result<()> {
         let game = &mut ctx.accounts.game;
         let key_holders = &mut ctx.accounts.key_holders;
@@ -186,36 +409,29 @@ pub mod solana_lastbid_program {
             BidErrorCode::GameEnded
         );
 
-        // Init buyer game data
-        if buyer_game_data.game_id != game.game_id {
-            buyer_game_data.game_id = game.game_id;
-            buyer_game_data.first_time_buying = true;
+        // Verify the purchase isn't made by an unknown program
+        if HUMANS_ONLY {
+            verify_human_purchase(
+                &ctx.accounts.instructions.to_account_info(),
+                APPROVED_INTEGRATORS,
+            )?;
         }
 
-        // Verify group number
-        let current_group_number = game.total_holders / (MAX_HOLDERS as u64);
-        require!(
-            group_number <= current_group_number,
-            BidErrorCode::IncorrectGroupNumber
-        );
+        // Init buyer game data, verifying the wallet is eligible in gated rounds
+        init_buyer_game_data(game, buyer_game_data, ctx.accounts.buyer.key(), &proof)?;
 
-        if game.total_holders == game.total_groups * (MAX_HOLDERS as u64) {
-            // if the buyer is the first person to buy keys in this group, then init the key_holders data
-            if current_group_number == group_number {
-                group_revenue_data.group_counter.push(0);
-                key_holders.holders = vec![];
-                game.total_groups += 1;
-            }
-        }
+        // Verify the anti-bot limits of the purchase
+        PURCHASE_LIMITS.verify(game, buyer_game_data, number_of_keys, &clock)?;
 
-        let already_exist = check_if_buyer_is_in_the_group(key_holders, ctx.accounts.buyer.key())?;
-        require!(
-            (group_number < current_group_number && already_exist) || !(group_number
-                == current_group_number
-                && !already_exist
-                && !buyer_game_data.first_time_buying),
-            BidErrorCode::IncorrectGroupNumber
-        );
+        // Verify group number
+        join_key_holder_group(
+            game,
+            key_holders,
+            group_revenue_data,
+            buyer_game_data,
+            ctx.accounts.buyer.key(),
+            group_number,
+        )?;
 
         // Verify the treasury wallet
         require_keys_eq!(
@@ -224,38 +440,134 @@ pub mod solana_lastbid_program {
             BidErrorCode::NotTreasury
         );
 
-        let extensible_time = if clock.unix_timestamp + MAX_TIMER >= game.timer_end {
-            clock.unix_timestamp + MAX_TIMER - game.timer_end
-        } else {
-            0
+        // Verify the team
+        require!((team as usize) < TEAM_COUNT, BidErrorCode::InvalidTeam);
+        let team_config = PURCHASE_TEAMS[team as usize];
+
+        // Verify the referral bound to the buyer, it's credited even without a referral code.
+        // A closed or deactivated bound referral is skipped, as if the buyer wasn't bound.
+        let bound_referral = bound_referral(
+            STICKY_REFERRAL,
+            buyer_game_data.bound_referral,
+            buyer_game_data.bound_referral_game_id,
+            game.game_id,
+        );
+        let referral_data = match (bound_referral, ctx.accounts.referral_account.as_mut()) {
+            (Some(bound_referral), Some(referral_data)) => {
+                require_keys_eq!(
+                    referral_data.key(),
+                    bound_referral,
+                    BidErrorCode::IncorrectReferralData
+                );
+                Some(referral_data).filter(|referral_data| referral_enabled(referral_data))
+            }
+            (Some(bound_referral), None) => {
+                // A closed referral can't be passed as a referral account anymore
+                let bound_referral_account = ctx
+                    .accounts
+                    .bound_referral_account
+                    .as_ref()
+                    .ok_or(BidErrorCode::MissingBoundReferral)?;
+                require!(
+                    !bound_referral_enabled(bound_referral_account, bound_referral)?,
+                    BidErrorCode::MissingBoundReferral
+                );
+                None
+            }
+            (None, _) => None,
         };
-        let available_keys = std::cmp::min(number_of_keys, (extensible_time / INC_TIME) as u64);
-        let new_end_time = game.timer_end + INC_TIME * (available_keys as i64);
+
+        // Clamp the keys to what the timer can still take
+        let (available_keys, new_end_time) = timer_extension(
+            game.timer_end,
+            clock.unix_timestamp,
+            MAX_TIMER,
+            INC_TIME,
+            number_of_keys,
+        )?;
+
+        // Verify the keys per round cap of the wallet
+        PURCHASE_LIMITS.verify_wallet_cap(buyer_game_data.keys_bought, available_keys)?;
 
         // Verify BID token balance to burn
-        require!(
-            game.bid_token_mint == ctx.accounts.bid_token_mint_account.key(),
-            BidErrorCode::IncorrectBidToken
-        );
-        let bid_token_balance = ctx.accounts.buyer_bid_token_account.amount;
-        require!(
-            bid_token_balance >= BID_TOKENS_PER_TX * available_keys,
-            BidErrorCode::InsufficientBidTokens
-        );
+        let bid_token_amount = bid_tokens_to_burn(
+            game,
+            ctx.accounts.bid_token_mint_account.key(),
+            ctx.accounts.buyer_bid_token_account.amount,
+            BID_TOKENS_PER_TX,
+            available_keys,
+        )?;
 
-        let data: Fees = calculate_fees_and_next_price(
+        // The referral share depends on the volume tier of the referrer
+        let referral_share = referral_data.as_deref().map_or(REFERRAL_SHARE, |referral_data| {
+            REFERRAL_VOLUME_TIERS
+                [referral_volume_tier(&REFERRAL_VOLUME_TIERS, referral_data.referred_volume)]
+            .share
+        });
+
+        let mut data: Fees = calculate_fees_and_next_price(
             BUY_FEE,
-            REFERRAL_SHARE,
-            LAST_BIDDER_SHARE,
-            KEY_HOLDERS_SHARE,
+            referral_share,
+            team_config.pot_share()?,
+            team_config.key_holders_share,
             available_keys,
             game.current_price,
             PRICE_INCREASE_RATE,
             DIVIDER,
-            false,
+            referral_data.is_some(),
+        )?;
+
+        // Give the buyer its part of the bound referral
+        if referral_data.is_some() {
+            apply_referral_rebate(
+                &mut data,
+                REFERRAL_REBATE_SHARE,
+                REFERRAL_REBATE_SOURCE,
+                DIVIDER,
+            )?;
+        }
+
+        // Verify the upper referral tiers of the bound referral
+        let second_tier_data = ctx
+            .accounts
+            .second_tier_referral_account
+            .as_ref()
+            .map(|tier_data| ReferralTierAccount::load(tier_data))
+            .transpose()?;
+        let third_tier_data = ctx
+            .accounts
+            .third_tier_referral_account
+            .as_ref()
+            .map(|tier_data| ReferralTierAccount::load(tier_data))
+            .transpose()?;
+        let referral_amounts = match referral_data.as_deref() {
+            Some(referral_data) => referral_tier_amounts(
+                &ReferralTierAccount::new(referral_data.key(), referral_data),
+                second_tier_data.as_ref(),
+                third_tier_data.as_ref(),
+                ctx.accounts.buyer.key(),
+                data.referral_amount,
+                SECOND_TIER_REFERRAL_SHARE,
+                THIRD_TIER_REFERRAL_SHARE,
+                DIVIDER,
+            )?,
+            None => [0; REFERRAL_TIERS],
+        };
+        data.treasury_amount = data
+            .treasury_amount
+            .safe_sub(referral_amounts[1].safe_add(referral_amounts[2])?)?;
+
+        let next_round_amount = team_config.next_round_amount(data.prize_pool_amount)?;
+        let airdrop_amount = mul_div(
+            data.prize_pool_amount.safe_sub(next_round_amount)?,
+            AIRDROP_SHARE,
+            DIVIDER,
         )?;
 
-        let max_amount = suggested_amount * (DIVIDER + PRICE_TOLERANCE) / DIVIDER;
+        // Rounding leftovers of the fee split are carried to the next round
+        let fee_dust = fee_split_dust(&data, &referral_amounts)?;
+
+        let max_amount = mul_div(suggested_amount, DIVIDER.safe_add(PRICE_TOLERANCE)?, DIVIDER)?;
         require!(
             max_amount >= data.total_amount,
             BidErrorCode::InvalidPaymentAmount
@@ -270,7 +582,7 @@ pub mod solana_lastbid_program {
                     to: ctx.accounts.chest_vault.to_account_info().clone(),
                 },
             ),
-            data.total_amount - data.treasury_amount,
+            data.total_amount.safe_sub(data.treasury_amount)?,
         )?;
 
         // transfer buy fee from buyer wallet to treasury
@@ -286,34 +598,39 @@ pub mod solana_lastbid_program {
         )?;
 
         // Burn $BID tokens
-        token::burn(
-            CpiContext::new(
-                ctx.accounts.token_program.to_account_info(),
-                token::Burn {
-                    mint: ctx
-                        .accounts
-                        .bid_token_mint_account
-                        .to_account_info()
-                        .clone(),
-                    from: ctx
-                        .accounts
-                        .buyer_bid_token_account
-                        .to_account_info()
-                        .clone(),
-                    authority: ctx.accounts.buyer.to_account_info().clone(),
-                },
-            ),
-            BID_TOKENS_PER_TX * available_keys,
+        burn_bid_tokens(
+            ctx.accounts.token_program.to_account_info(),
+            ctx.accounts.bid_token_mint_account.to_account_info(),
+            ctx.accounts.buyer_bid_token_account.to_account_info(),
+            ctx.accounts.buyer.to_account_info(),
+            bid_token_amount,
         )?;
 
-        adjust_revenue(
-            ctx.accounts.buyer.key(),
-            key_holders,
-            group_revenue_data,
-            data.key_holders_amount,
-            group_number,
-            game.total_keys,
-        )?;
+        // Revenue is shared by weight, equal to the keys unless earlier keys are weighted up
+        let weight = purchase_weight(REVENUE_WEIGHTING, game.total_keys, available_keys)?;
+
+        // In key token mode the key holders share follows the key token balances instead of the groups
+        let revenue_dust = if KEY_TOKEN_MODE {
+            let key_mint_state = ctx
+                .accounts
+                .key_mint_state
+                .as_mut()
+                .ok_or(BidErrorCode::MissingKeyTokenAccounts)?;
+            require!(
+                key_mint_state.game_id == game.game_id,
+                BidErrorCode::IncorrectGameId
+            );
+            data.key_holders_amount
+                .safe_sub(credit_key_revenue(key_mint_state, data.key_holders_amount)?)?
+        } else {
+            data.key_holders_amount.safe_sub(distribute_purchase_revenue(
+                key_holders,
+                group_revenue_data,
+                data.key_holders_amount,
+                group_number,
+                game.total_weight,
+            )?)?
+        };
 
         update_key_holders(
             key_holders,
@@ -321,43 +638,164 @@ pub mod solana_lastbid_program {
             available_keys,
             clock.unix_timestamp,
         )?;
-
-        if buyer_game_data.first_time_buying {
-            game.total_holders += 1;
-            buyer_game_data.first_time_buying = false;
+        add_holder_weight(key_holders, ctx.accounts.buyer.key(), weight)?;
+
+        // Mint the purchased keys as key tokens
+        if KEY_TOKEN_MODE {
+            mint_key_tokens(
+                ctx.accounts
+                    .key_mint_state
+                    .as_mut()
+                    .ok_or(BidErrorCode::MissingKeyTokenAccounts)?,
+                ctx.accounts
+                    .key_mint
+                    .as_ref()
+                    .ok_or(BidErrorCode::MissingKeyTokenAccounts)?,
+                ctx.accounts
+                    .buyer_key_token_account
+                    .as_ref()
+                    .ok_or(BidErrorCode::MissingKeyTokenAccounts)?,
+                ctx.accounts
+                    .buyer_key_checkpoint
+                    .as_mut()
+                    .ok_or(BidErrorCode::MissingKeyTokenAccounts)?,
+                ctx.accounts
+                    .key_token_program
+                    .as_ref()
+                    .ok_or(BidErrorCode::MissingKeyTokenAccounts)?,
+                game.game_id,
+                available_keys,
+            )?;
         }
 
-        // Update game state
-        game.last_bidder = ctx.accounts.buyer.key();
-        game.total_keys += available_keys;
-        game.total_amount += data.total_amount;
-        game.current_price = data.next_key_price;
-        game.prize_pool_balance += data.prize_pool_amount;
-        game.last_purchase_time = clock.unix_timestamp;
-        game.timer_end = new_end_time;
+        // Update buyer data and game state
+        let new_buyer = record_purchase(
+            game,
+            buyer_game_data,
+            &Purchase {
+                buyer: ctx.accounts.buyer.key(),
+                team,
+                keys: available_keys,
+                weight,
+                fees: &data,
+                next_round_amount,
+                airdrop_amount,
+                timer_end: new_end_time,
+            },
+            &clock,
+        )?;
+
+        // update the referrer data of the bound referral
+        if let Some(referral_data) = referral_data {
+            credit_referral_tiers(
+                game,
+                &mut ctx.accounts.vault_ledger,
+                referral_data,
+                ctx.accounts.second_tier_referral_account.as_deref(),
+                ctx.accounts.third_tier_referral_account.as_deref(),
+                referral_amounts,
+            )?;
+
+            // record the purchase in the referrer's stats of the round
+            let referral_game_stats = ctx
+                .accounts
+                .referral_game_stats
+                .as_mut()
+                .ok_or(BidErrorCode::IncorrectReferralData)?;
+            record_referral_game_stats(
+                referral_game_stats,
+                referral_data.key(),
+                game.game_id,
+                new_buyer,
+                available_keys,
+                data.total_amount,
+                referral_amounts[0],
+            )?;
+
+            // move the referrer up a tier once its referred volume is reached
+            if let Some(tier) =
+                record_referred_volume(referral_data, &REFERRAL_VOLUME_TIERS, data.total_amount)?
+            {
+                emit!(ReferralTierUpgradedEvent {
+                    referrer: referral_data.owner,
+                    ref_code: referral_data.ref_code.clone(),
+                    tier,
+                    share: REFERRAL_VOLUME_TIERS[tier as usize].share,
+                    referred_volume: referral_data.referred_volume,
+                    timestamp: clock.unix_timestamp,
+                });
+            }
+        }
 
         // adjust revenue of the previous key_holders
-        game.revenue_earned += data.key_holders_amount;
+        game.revenue_earned = game
+            .revenue_earned
+            .safe_add(data.key_holders_amount.safe_sub(revenue_dust)?)?;
+        game.dust_carry = game.dust_carry.safe_add(fee_dust.safe_add(revenue_dust)?)?;
+
+        // Enter the airdrop draw with the ticket passed as the first remaining account,
+        // it is settled by settle_airdrop once the committed randomness is revealed
+        let odds = airdrop_odds(
+            data.total_amount,
+            AIRDROP_MIN_PURCHASE,
+            AIRDROP_ODDS_PER_SOL,
+            AIRDROP_MAX_ODDS,
+        )?;
+        if let Some(airdrop_ticket) = ctx.remaining_accounts.first().filter(|_| odds > 0) {
+            let prize = mul_div(game.airdrop_pot, AIRDROP_PAYOUT_SHARE, DIVIDER)?;
+            let randomness_account = ctx
+                .accounts
+                .randomness_account
+                .as_ref()
+                .map(|account| account.to_account_info());
+            commit_airdrop_ticket(
+                airdrop_ticket,
+                randomness_account.as_ref(),
+                AIRDROP_ORACLE,
+                ctx.accounts.buyer.key(),
+                game.game_id,
+                odds,
+                prize,
+                &clock,
+            )?;
+
+            emit!(AirdropTicketCommittedEvent {
+                game_id: game.game_id,
+                buyer: ctx.accounts.buyer.key(),
+                odds,
+                commit_slot: clock.slot,
+                timestamp: clock.unix_timestamp,
+            });
+        }
 
         emit!(KeyPurchasedEvent {
             game_id: game.game_id,
             buyer: ctx.accounts.buyer.key(),
             amount: data.total_amount,
             number_of_keys: available_keys,
+            team,
             new_price: game.current_price,
             timer_end: game.timer_end,
             purchased_at: clock.unix_timestamp,
         });
 
+        assert_vault_solvent(
+            &ctx.accounts.chest_vault.to_account_info(),
+            Some(&ctx.accounts.game),
+            &ctx.accounts.vault_ledger,
+        )?;
+
         Ok(())
     }
 
-    pub fn buy_keys_with_referral_code(
-        ctx: Context<BuyKeysWithReferralCode>,
+    pub fn buy_keys_with_referral_code<'info>(
+        ctx: Context<'_, '_, 'info, 'info, BuyKeysWithReferralCode<'info>>,
         group_number: u64,
         ref_code: String,
         suggested_amount: u64,
         number_of_keys: u64,
+        team: u8,
+        proof: Vec<[u8; 32]>,
     ) -> Result<()> {
         let game = &mut ctx.accounts.game;
         let key_holders = &mut ctx.accounts.key_holders;
@@ -372,36 +810,29 @@ pub mod solana_lastbid_program {
             BidErrorCode::GameEnded
         );
 
-        // Init buyer game data
-        if buyer_game_data.game_id != game.game_id {
-            buyer_game_data.game_id = game.game_id;
-            buyer_game_data.first_time_buying = true;
+        // Verify the purchase isn't made by an unknown program
+        if HUMANS_ONLY {
+            verify_human_purchase(
+                &ctx.accounts.instructions.to_account_info(),
+                APPROVED_INTEGRATORS,
+            )?;
         }
 
-        // Verify group number
-        let current_group_number = game.total_holders / (MAX_HOLDERS as u64);
-        require!(
-            group_number <= current_group_number,
-            BidErrorCode::IncorrectGroupNumber
-        );
+        // Init buyer game data, verifying the wallet is eligible in gated rounds
+        init_buyer_game_data(game, buyer_game_data, ctx.accounts.buyer.key(), &proof)?;
 
-        if game.total_holders == game.total_groups * (MAX_HOLDERS as u64) {
-            // if the buyer is the first person to buy keys in this group, then init the key_holders data
-            if current_group_number == group_number {
-                group_revenue_data.group_counter.push(0);
-                key_holders.holders = vec![];
-                game.total_groups += 1;
-            }
-        }
+        // Verify the anti-bot limits of the purchase
+        PURCHASE_LIMITS.verify(game, buyer_game_data, number_of_keys, &clock)?;
 
-        let already_exist = check_if_buyer_is_in_the_group(key_holders, ctx.accounts.buyer.key())?;
-        require!(
-            (group_number < current_group_number && already_exist) || !(group_number
-                == current_group_number
-                && !already_exist
-                && !buyer_game_data.first_time_buying),
-            BidErrorCode::IncorrectGroupNumber
-        );
+        // Verify group number
+        join_key_holder_group(
+            game,
+            key_holders,
+            group_revenue_data,
+            buyer_game_data,
+            ctx.accounts.buyer.key(),
+            group_number,
+        )?;
 
         // Verify the treasury wallet
         require_keys_eq!(
@@ -410,38 +841,78 @@ pub mod solana_lastbid_program {
             BidErrorCode::NotTreasury
         );
 
-        // Verify the referrer data
+        // Verify the team
+        require!((team as usize) < TEAM_COUNT, BidErrorCode::InvalidTeam);
+        let team_config = PURCHASE_TEAMS[team as usize];
+
+        // Verify the referrer data, the referral account is the one the code registry points to
+        verify_registered_referral(
+            &ctx.accounts.referral_code,
+            &ref_code,
+            referral_data.key(),
+        )?;
         require!(
             referral_data.owner.key() != ctx.accounts.buyer.key()
-                && referral_data.ref_code == ref_code
-                && referral_data.active,
+                && referral_enabled(referral_data),
             BidErrorCode::IncorrectReferralData
         );
 
-        let extensible_time = if clock.unix_timestamp + MAX_TIMER >= game.timer_end {
-            clock.unix_timestamp + MAX_TIMER - game.timer_end
-        } else {
-            0
+        // Verify the referral bound to the buyer, the first referral account used sticks to the wallet.
+        // Once the bound referral is closed or deactivated, the buyer is bound to the one it uses.
+        let bound_referral = bound_referral(
+            STICKY_REFERRAL,
+            buyer_game_data.bound_referral,
+            buyer_game_data.bound_referral_game_id,
+            game.game_id,
+        );
+        let bound_enabled = match (bound_referral, ctx.accounts.bound_referral_account.as_ref()) {
+            (Some(bound_referral), Some(bound_referral_account)) => Some(bound_referral_enabled(
+                bound_referral_account,
+                bound_referral,
+            )?),
+            _ => None,
         };
-        let available_keys = std::cmp::min(number_of_keys, (extensible_time / INC_TIME) as u64);
-        let new_end_time = game.timer_end + INC_TIME * (available_keys as i64);
+        if let Some(binding) = referral_binding(
+            STICKY_REFERRAL,
+            bound_referral,
+            referral_data.key(),
+            bound_enabled,
+        )? {
+            buyer_game_data.bound_referral = Some(binding);
+            buyer_game_data.bound_referral_game_id = game.game_id;
+        }
+
+        // Clamp the keys to what the timer can still take
+        let (available_keys, new_end_time) = timer_extension(
+            game.timer_end,
+            clock.unix_timestamp,
+            MAX_TIMER,
+            INC_TIME,
+            number_of_keys,
+        )?;
+
+        // Verify the keys per round cap of the wallet
+        PURCHASE_LIMITS.verify_wallet_cap(buyer_game_data.keys_bought, available_keys)?;
 
         // Verify BID token balance to burn
-        require!(
-            game.bid_token_mint == ctx.accounts.bid_token_mint_account.key(),
-            BidErrorCode::IncorrectBidToken
-        );
-        let bid_token_balance = ctx.accounts.buyer_bid_token_account.amount;
-        require!(
-            bid_token_balance >= BID_TOKENS_PER_TX * available_keys,
-            BidErrorCode::InsufficientBidTokens
-        );
+        let bid_token_amount = bid_tokens_to_burn(
+            game,
+            ctx.accounts.bid_token_mint_account.key(),
+            ctx.accounts.buyer_bid_token_account.amount,
+            BID_TOKENS_PER_TX,
+            available_keys,
+        )?;
+
+        // The referral share depends on the volume tier of the referrer
+        let referral_share = REFERRAL_VOLUME_TIERS
+            [referral_volume_tier(&REFERRAL_VOLUME_TIERS, referral_data.referred_volume)]
+        .share;
 
-        let data = calculate_fees_and_next_price(
+        let mut data = calculate_fees_and_next_price(
             BUY_FEE,
-            REFERRAL_SHARE,
-            LAST_BIDDER_SHARE,
-            KEY_HOLDERS_SHARE,
+            referral_share,
+            team_config.pot_share()?,
+            team_config.key_holders_share,
             available_keys,
             game.current_price,
             PRICE_INCREASE_RATE,
@@ -449,7 +920,52 @@ pub mod solana_lastbid_program {
             true,
         )?;
 
-        let max_amount = suggested_amount * (DIVIDER + PRICE_TOLERANCE) / DIVIDER;
+        // Give the buyer its part of the referral
+        apply_referral_rebate(
+            &mut data,
+            REFERRAL_REBATE_SHARE,
+            REFERRAL_REBATE_SOURCE,
+            DIVIDER,
+        )?;
+
+        // Verify the upper referral tiers, their bonus is taken from the treasury fee
+        let second_tier_data = ctx
+            .accounts
+            .second_tier_referral_account
+            .as_ref()
+            .map(|tier_data| ReferralTierAccount::load(tier_data))
+            .transpose()?;
+        let third_tier_data = ctx
+            .accounts
+            .third_tier_referral_account
+            .as_ref()
+            .map(|tier_data| ReferralTierAccount::load(tier_data))
+            .transpose()?;
+        let referral_amounts = referral_tier_amounts(
+            &ReferralTierAccount::new(referral_data.key(), referral_data),
+            second_tier_data.as_ref(),
+            third_tier_data.as_ref(),
+            ctx.accounts.buyer.key(),
+            data.referral_amount,
+            SECOND_TIER_REFERRAL_SHARE,
+            THIRD_TIER_REFERRAL_SHARE,
+            DIVIDER,
+        )?;
+        data.treasury_amount = data
+            .treasury_amount
+            .safe_sub(referral_amounts[1].safe_add(referral_amounts[2])?)?;
+
+        let next_round_amount = team_config.next_round_amount(data.prize_pool_amount)?;
+        let airdrop_amount = mul_div(
+            data.prize_pool_amount.safe_sub(next_round_amount)?,
+            AIRDROP_SHARE,
+            DIVIDER,
+        )?;
+
+        // Rounding leftovers of the fee split are carried to the next round
+        let fee_dust = fee_split_dust(&data, &referral_amounts)?;
+
+        let max_amount = mul_div(suggested_amount, DIVIDER.safe_add(PRICE_TOLERANCE)?, DIVIDER)?;
         require!(
             max_amount >= data.total_amount,
             BidErrorCode::InvalidPaymentAmount
@@ -464,7 +980,7 @@ pub mod solana_lastbid_program {
                     to: ctx.accounts.chest_vault.to_account_info().clone(),
                 },
             ),
-            data.total_amount - data.treasury_amount,
+            data.total_amount.safe_sub(data.treasury_amount)?,
         )?;
         // transfer buy fee from buyer wallet to treasury
         system_program::transfer(
@@ -479,34 +995,39 @@ pub mod solana_lastbid_program {
         )?;
 
         // Burn $BID tokens
-        token::burn(
-            CpiContext::new(
-                ctx.accounts.token_program.to_account_info(),
-                token::Burn {
-                    mint: ctx
-                        .accounts
-                        .bid_token_mint_account
-                        .to_account_info()
-                        .clone(),
-                    from: ctx
-                        .accounts
-                        .buyer_bid_token_account
-                        .to_account_info()
-                        .clone(),
-                    authority: ctx.accounts.buyer.to_account_info().clone(),
-                },
-            ),
-            BID_TOKENS_PER_TX * available_keys,
+        burn_bid_tokens(
+            ctx.accounts.token_program.to_account_info(),
+            ctx.accounts.bid_token_mint_account.to_account_info(),
+            ctx.accounts.buyer_bid_token_account.to_account_info(),
+            ctx.accounts.buyer.to_account_info(),
+            bid_token_amount,
         )?;
 
-        adjust_revenue(
-            ctx.accounts.buyer.key(),
-            key_holders,
-            group_revenue_data,
-            data.key_holders_amount,
-            group_number,
-            game.total_keys,
-        )?;
+        // Revenue is shared by weight, equal to the keys unless earlier keys are weighted up
+        let weight = purchase_weight(REVENUE_WEIGHTING, game.total_keys, available_keys)?;
+
+        // In key token mode the key holders share follows the key token balances instead of the groups
+        let revenue_dust = if KEY_TOKEN_MODE {
+            let key_mint_state = ctx
+                .accounts
+                .key_mint_state
+                .as_mut()
+                .ok_or(BidErrorCode::MissingKeyTokenAccounts)?;
+            require!(
+                key_mint_state.game_id == game.game_id,
+                BidErrorCode::IncorrectGameId
+            );
+            data.key_holders_amount
+                .safe_sub(credit_key_revenue(key_mint_state, data.key_holders_amount)?)?
+        } else {
+            data.key_holders_amount.safe_sub(distribute_purchase_revenue(
+                key_holders,
+                group_revenue_data,
+                data.key_holders_amount,
+                group_number,
+                game.total_weight,
+            )?)?
+        };
 
         update_key_holders(
             key_holders,
@@ -514,39 +1035,223 @@ pub mod solana_lastbid_program {
             available_keys,
             clock.unix_timestamp,
         )?;
-
-        if buyer_game_data.first_time_buying {
-            game.total_holders += 1;
-            buyer_game_data.first_time_buying = false;
+        add_holder_weight(key_holders, ctx.accounts.buyer.key(), weight)?;
+
+        // Mint the purchased keys as key tokens
+        if KEY_TOKEN_MODE {
+            mint_key_tokens(
+                ctx.accounts
+                    .key_mint_state
+                    .as_mut()
+                    .ok_or(BidErrorCode::MissingKeyTokenAccounts)?,
+                ctx.accounts
+                    .key_mint
+                    .as_ref()
+                    .ok_or(BidErrorCode::MissingKeyTokenAccounts)?,
+                ctx.accounts
+                    .buyer_key_token_account
+                    .as_ref()
+                    .ok_or(BidErrorCode::MissingKeyTokenAccounts)?,
+                ctx.accounts
+                    .buyer_key_checkpoint
+                    .as_mut()
+                    .ok_or(BidErrorCode::MissingKeyTokenAccounts)?,
+                ctx.accounts
+                    .key_token_program
+                    .as_ref()
+                    .ok_or(BidErrorCode::MissingKeyTokenAccounts)?,
+                game.game_id,
+                available_keys,
+            )?;
         }
 
-        // Update game state
-        game.last_bidder = ctx.accounts.buyer.key();
-        game.total_keys += available_keys;
-        game.total_amount += data.total_amount;
-        game.current_price = data.next_key_price;
-        game.prize_pool_balance += data.prize_pool_amount;
-        game.last_purchase_time = clock.unix_timestamp;
-        game.timer_end = new_end_time;
+        // Update buyer data and game state
+        let new_buyer = record_purchase(
+            game,
+            buyer_game_data,
+            &Purchase {
+                buyer: ctx.accounts.buyer.key(),
+                team,
+                keys: available_keys,
+                weight,
+                fees: &data,
+                next_round_amount,
+                airdrop_amount,
+                timer_end: new_end_time,
+            },
+            &clock,
+        )?;
+
+        // update the referrer data of each tier
+        credit_referral_tiers(
+            game,
+            &mut ctx.accounts.vault_ledger,
+            referral_data,
+            ctx.accounts.second_tier_referral_account.as_deref(),
+            ctx.accounts.third_tier_referral_account.as_deref(),
+            referral_amounts,
+        )?;
+
+        // record the purchase in the referrer's stats of the round
+        record_referral_game_stats(
+            &mut ctx.accounts.referral_game_stats,
+            referral_data.key(),
+            game.game_id,
+            new_buyer,
+            available_keys,
+            data.total_amount,
+            referral_amounts[0],
+        )?;
 
-        // update the referrer data
-        referral_data.total_earned += data.referral_amount;
-        game.referral_earned += data.referral_amount;
+        // move the referrer up a tier once its referred volume is reached
+        if let Some(tier) =
+            record_referred_volume(referral_data, &REFERRAL_VOLUME_TIERS, data.total_amount)?
+        {
+            emit!(ReferralTierUpgradedEvent {
+                referrer: referral_data.owner,
+                ref_code: referral_data.ref_code.clone(),
+                tier,
+                share: REFERRAL_VOLUME_TIERS[tier as usize].share,
+                referred_volume: referral_data.referred_volume,
+                timestamp: clock.unix_timestamp,
+            });
+        }
 
         // adjust revenue of the previous key_holders
-        game.revenue_earned += data.key_holders_amount;
+        game.revenue_earned = game
+            .revenue_earned
+            .safe_add(data.key_holders_amount.safe_sub(revenue_dust)?)?;
+        game.dust_carry = game.dust_carry.safe_add(fee_dust.safe_add(revenue_dust)?)?;
+
+        // Enter the airdrop draw with the ticket passed as the first remaining account,
+        // it is settled by settle_airdrop once the committed randomness is revealed
+        let odds = airdrop_odds(
+            data.total_amount,
+            AIRDROP_MIN_PURCHASE,
+            AIRDROP_ODDS_PER_SOL,
+            AIRDROP_MAX_ODDS,
+        )?;
+        if let Some(airdrop_ticket) = ctx.remaining_accounts.first().filter(|_| odds > 0) {
+            let prize = mul_div(game.airdrop_pot, AIRDROP_PAYOUT_SHARE, DIVIDER)?;
+            let randomness_account = ctx
+                .accounts
+                .randomness_account
+                .as_ref()
+                .map(|account| account.to_account_info());
+            commit_airdrop_ticket(
+                airdrop_ticket,
+                randomness_account.as_ref(),
+                AIRDROP_ORACLE,
+                ctx.accounts.buyer.key(),
+                game.game_id,
+                odds,
+                prize,
+                &clock,
+            )?;
+
+            emit!(AirdropTicketCommittedEvent {
+                game_id: game.game_id,
+                buyer: ctx.accounts.buyer.key(),
+                odds,
+                commit_slot: clock.slot,
+                timestamp: clock.unix_timestamp,
+            });
+        }
 
         emit!(KeyPurchasedWithReferralEvent {
             game_id: game.game_id,
             buyer: ctx.accounts.buyer.key(),
             ref_code: referral_data.ref_code.clone(),
             amount: data.total_amount,
+            rebate: data.rebate_amount,
             number_of_keys: available_keys,
+            team,
             new_price: game.current_price,
             timer_end: game.timer_end,
             purchased_at: clock.unix_timestamp,
         });
 
+        assert_vault_solvent(
+            &ctx.accounts.chest_vault.to_account_info(),
+            Some(&ctx.accounts.game),
+            &ctx.accounts.vault_ledger,
+        )?;
+
+        Ok(())
+    }
+
+    pub fn open_airdrop_ticket(ctx: Context<OpenAirdropTicket>) -> Result<()> {
+        let airdrop_ticket = &mut ctx.accounts.airdrop_ticket;
+
+        airdrop_ticket.buyer = ctx.accounts.buyer.key();
+        airdrop_ticket.game_id = 0;
+        airdrop_ticket.odds = 0;
+        airdrop_ticket.prize = 0;
+        airdrop_ticket.commit_slot = 0;
+        airdrop_ticket.randomness_account = None;
+
+        Ok(())
+    }
+
+    pub fn settle_airdrop(ctx: Context<SettleAirdrop>) -> Result<()> {
+        let game = &mut ctx.accounts.game;
+        let airdrop_ticket = &mut ctx.accounts.airdrop_ticket;
+        let clock = Clock::get()?;
+
+        // Verify the randomness account is the one committed to by the purchase
+        let randomness_account = ctx
+            .accounts
+            .randomness_account
+            .as_ref()
+            .map(|account| account.to_account_info());
+        require!(
+            randomness_account.as_ref().map(|account| account.key())
+                == airdrop_ticket.randomness_account,
+            BidErrorCode::InvalidRandomnessAccount
+        );
+
+        let slot_hashes = ctx.accounts.slot_hashes.to_account_info();
+        let source = airdrop_randomness(
+            &slot_hashes,
+            randomness_account.as_ref(),
+            AIRDROP_ORACLE,
+            AIRDROP_REVEAL_DELAY,
+        )?;
+        let odds = airdrop_ticket.odds;
+        let airdrop_prize = settle_airdrop_ticket(
+            airdrop_ticket,
+            source.as_ref(),
+            &clock,
+            game.game_id,
+            game.airdrop_pot,
+            AIRDROP_SETTLE_WINDOW,
+            DIVIDER,
+        )?;
+
+        if airdrop_prize > 0 {
+            game.airdrop_pot = game.airdrop_pot.safe_sub(airdrop_prize)?;
+
+            transfer_sol(
+                ctx.accounts.chest_vault.to_account_info().clone(),
+                ctx.accounts.buyer.to_account_info(),
+                airdrop_prize,
+            )?;
+
+            emit!(AirdropEvent {
+                game_id: airdrop_ticket.game_id,
+                winner: ctx.accounts.buyer.key(),
+                amount: airdrop_prize,
+                odds,
+                timestamp: clock.unix_timestamp,
+            });
+        }
+
+        assert_vault_solvent(
+            &ctx.accounts.chest_vault.to_account_info(),
+            Some(&ctx.accounts.game),
+            &ctx.accounts.vault_ledger,
+        )?;
+
         Ok(())
     }
 
@@ -555,17 +1260,46 @@ pub mod solana_lastbid_program {
         ref_code: String,
     ) -> Result<()> {
         let referral_account = &mut ctx.accounts.referral_account;
+        let referral_code = &mut ctx.accounts.referral_code;
+        let clock = Clock::get()?;
 
         require!(
             !referral_account.active,
             BidErrorCode::AlreadyActivedReferralAccount
         );
 
+        // Verify the code, the registry PDA can only be created once per normalized code
+        let ref_code = normalize_ref_code(&ref_code);
+        validate_ref_code(&ref_code)?;
+
+        // Verify the referrer of the new referral account
+        if let Some(parent_referral_data) = ctx.accounts.parent_referral_account.as_ref() {
+            require!(
+                referral_enabled(parent_referral_data)
+                    && parent_referral_data.owner != ctx.accounts.payer.key(),
+                BidErrorCode::IncorrectReferralData
+            );
+        }
+
         referral_account.active = true;
         referral_account.owner = ctx.accounts.payer.key();
-        referral_account.ref_code = ref_code;
+        referral_account.ref_code = ref_code.clone();
         referral_account.total_earned = 0;
-        referral_account.created_at = Clock::get()?.unix_timestamp;
+        referral_account.tier_earned = [0; REFERRAL_TIERS];
+        referral_account.referred_volume = 0;
+        referral_account.tier = 0;
+        referral_account.payout_wallet = None;
+        referral_account.admin_disabled = false;
+        referral_account.referred_by = ctx
+            .accounts
+            .parent_referral_account
+            .as_ref()
+            .map(|parent_referral_data| parent_referral_data.key());
+        referral_account.created_at = clock.unix_timestamp;
+
+        referral_code.ref_code = ref_code;
+        referral_code.referral_account = referral_account.key();
+        referral_code.created_at = clock.unix_timestamp;
 
         emit!(ReferralAccountCreatedEvent {
             owner: referral_account.owner,
@@ -576,26 +1310,173 @@ pub mod solana_lastbid_program {
         Ok(())
     }
 
-    pub fn release_main_prize(ctx: Context<ReleaseMainPrize>) -> Result<()> {
-        let game = &mut ctx.accounts.game;
-        let ownership = &ctx.accounts.ownership;
-        let chest_vault = &mut ctx.accounts.chest_vault;
-        let clock = Clock::get()?;
-
-        // Verify the ownership
-        ownership.verify_ownership(ctx.accounts.owner.key())?;
+    pub fn set_referral_active(ctx: Context<UpdateReferralAccount>, active: bool) -> Result<()> {
+        let referral_data = &mut ctx.accounts.referral_account;
 
-        // Verify the treasury wallet
         require_keys_eq!(
-            ctx.accounts.treasury.key(),
-            game.treasury,
-            BidErrorCode::NotTreasury
+            referral_data.owner,
+            ctx.accounts.owner.key(),
+            BidErrorCode::IncorrectReferralData
         );
+        // The code stays disabled while the admin disables it, whatever its owner sets
+        referral_data.active = active;
+
+        emit!(ReferralAccountUpdatedEvent {
+            referral_account: referral_data.key(),
+            owner: referral_data.owner,
+            payout_wallet: referral_data.payout_wallet,
+            active: referral_data.active,
+            admin_disabled: referral_data.admin_disabled,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
 
-        require!(
-            clock.unix_timestamp >= game.timer_end,
-            BidErrorCode::TimerNotExpired
-        );
+        Ok(())
+    }
+
+    pub fn transfer_referral_account(
+        ctx: Context<UpdateReferralAccount>,
+        new_owner: Pubkey,
+    ) -> Result<()> {
+        let referral_data = &mut ctx.accounts.referral_account;
+
+        require_keys_eq!(
+            referral_data.owner,
+            ctx.accounts.owner.key(),
+            BidErrorCode::IncorrectReferralData
+        );
+
+        // The payout wallet belongs to the previous owner
+        referral_data.owner = new_owner;
+        referral_data.payout_wallet = None;
+
+        emit!(ReferralAccountUpdatedEvent {
+            referral_account: referral_data.key(),
+            owner: referral_data.owner,
+            payout_wallet: referral_data.payout_wallet,
+            active: referral_data.active,
+            admin_disabled: referral_data.admin_disabled,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    pub fn set_referral_payout_wallet(
+        ctx: Context<UpdateReferralAccount>,
+        payout_wallet: Option<Pubkey>,
+    ) -> Result<()> {
+        let referral_data = &mut ctx.accounts.referral_account;
+
+        require_keys_eq!(
+            referral_data.owner,
+            ctx.accounts.owner.key(),
+            BidErrorCode::IncorrectReferralData
+        );
+
+        referral_data.payout_wallet = payout_wallet;
+
+        emit!(ReferralAccountUpdatedEvent {
+            referral_account: referral_data.key(),
+            owner: referral_data.owner,
+            payout_wallet: referral_data.payout_wallet,
+            active: referral_data.active,
+            admin_disabled: referral_data.admin_disabled,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    pub fn admin_set_referral_disabled(
+        ctx: Context<AdminUpdateReferralAccount>,
+        disabled: bool,
+    ) -> Result<()> {
+        let referral_data = &mut ctx.accounts.referral_account;
+        let ownership = &ctx.accounts.ownership;
+
+        ownership.verify_ownership(ctx.accounts.owner.key())?;
+
+        // The owner's own pause is left untouched, re-enabling doesn't resume a paused code
+        referral_data.admin_disabled = disabled;
+
+        emit!(ReferralAccountUpdatedEvent {
+            referral_account: referral_data.key(),
+            owner: referral_data.owner,
+            payout_wallet: referral_data.payout_wallet,
+            active: referral_data.active,
+            admin_disabled: referral_data.admin_disabled,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    pub fn close_referral_account(ctx: Context<CloseReferralAccount>) -> Result<()> {
+        let referral_data = &ctx.accounts.referral_account;
+
+        require_keys_eq!(
+            referral_data.owner,
+            ctx.accounts.owner.key(),
+            BidErrorCode::IncorrectReferralData
+        );
+        require_keys_eq!(
+            ctx.accounts.referral_code.referral_account,
+            referral_data.key(),
+            BidErrorCode::IncorrectReferralData
+        );
+        // Unclaimed bonus has to be claimed first
+        require!(
+            referral_data.total_earned == 0,
+            BidErrorCode::UnclaimedReferralBonus
+        );
+
+        emit!(ReferralAccountClosedEvent {
+            owner: referral_data.owner,
+            ref_code: referral_data.ref_code.clone(),
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    pub fn view_referral_game_stats(
+        ctx: Context<ViewReferralGameStats>,
+    ) -> Result<ReferralGameStats> {
+        let stats = &ctx.accounts.referral_game_stats;
+
+        Ok(ReferralGameStats {
+            referral_account: stats.referral_account,
+            game_id: stats.game_id,
+            referred_buyers: stats.referred_buyers,
+            keys: stats.keys,
+            volume: stats.volume,
+            bonus_earned: stats.bonus_earned,
+        })
+    }
+
+    pub fn release_main_prize<'info>(
+        ctx: Context<'_, '_, 'info, 'info, ReleaseMainPrize<'info>>,
+    ) -> Result<()> {
+        let game = &mut ctx.accounts.game;
+        let ownership = &ctx.accounts.ownership;
+        let chest_vault = &mut ctx.accounts.chest_vault;
+        let group_revenue_data = &mut ctx.accounts.group_revenue_counter;
+        let clock = Clock::get()?;
+
+        // Verify the ownership
+        ownership.verify_ownership(ctx.accounts.owner.key())?;
+
+        // Verify the treasury wallet
+        require_keys_eq!(
+            ctx.accounts.treasury.key(),
+            game.treasury,
+            BidErrorCode::NotTreasury
+        );
+
+        require!(
+            clock.unix_timestamp >= game.timer_end,
+            BidErrorCode::TimerNotExpired
+        );
 
         require!(!game.prized, BidErrorCode::AlreadyPrized);
 
@@ -605,25 +1486,92 @@ pub mod solana_lastbid_program {
             if ctx.accounts.last_bidder.key() == game.last_bidder {
                 game.prized = true;
 
-                let prize_fee_amount = game.prize_pool_balance * DISTRIBUTION_FEE / DIVIDER;
+                // The team of the last bidder decides how the main prize is split
+                let team_config = TEAMS[game.last_bidder_team as usize];
+                let key_holders_prize = mul_div(
+                    game.prize_pool_balance,
+                    team_config.final_key_holders_share,
+                    DIVIDER,
+                )?;
+                let next_round_prize = mul_div(
+                    game.prize_pool_balance,
+                    team_config.final_next_round_share,
+                    DIVIDER,
+                )?;
+                let winner_prize = game
+                    .prize_pool_balance
+                    .safe_sub(key_holders_prize)?
+                    .safe_sub(next_round_prize)?;
+
+                // distribute the key holders part over every group, or every key token in key token mode,
+                // what can't be split is carried as dust
+                let distributed = if KEY_TOKEN_MODE {
+                    let key_mint_state = ctx
+                        .accounts
+                        .key_mint_state
+                        .as_mut()
+                        .ok_or(BidErrorCode::MissingKeyTokenAccounts)?;
+                    require!(
+                        key_mint_state.game_id == game.game_id,
+                        BidErrorCode::IncorrectGameId
+                    );
+                    credit_key_revenue(key_mint_state, key_holders_prize)?
+                } else {
+                    distribute_to_all_groups(group_revenue_data, key_holders_prize, game.total_weight)?
+                };
+                game.revenue_earned = game.revenue_earned.safe_add(distributed)?;
+                game.next_round_seed = game.next_round_seed.safe_add(next_round_prize)?;
+                game.dust_carry = game
+                    .dust_carry
+                    .safe_add(key_holders_prize.safe_sub(distributed)?)?;
+
+                let prize_fee_amount = mul_div(winner_prize, DISTRIBUTION_FEE, DIVIDER)?;
                 // transfer main prize fee to treasury
                 transfer_sol(
                     chest_vault.to_account_info().clone(),
                     ctx.accounts.treasury.to_account_info(),
                     prize_fee_amount,
                 )?;
-                // transfer main prize to last bidder
-                transfer_sol(
-                    chest_vault.to_account_info().clone(),
-                    ctx.accounts.last_bidder.to_account_info(),
-                    game.prize_pool_balance - prize_fee_amount,
-                )?;
+                // transfer main prize to last bidder, or stream it when it vests.
+                // The stream opened by open_prize_stream is passed as the first remaining account
+                if PRIZE_VESTING_PERIOD > 0 {
+                    let mut prize_stream = load_prize_stream(
+                        ctx.remaining_accounts
+                            .first()
+                            .ok_or(BidErrorCode::MissingPrizeStream)?,
+                        game.game_id,
+                    )?;
+                    start_prize_stream(
+                        &mut prize_stream,
+                        &mut ctx.accounts.vault_ledger,
+                        game.game_id,
+                        game.last_bidder,
+                        winner_prize.safe_sub(prize_fee_amount)?,
+                        clock.unix_timestamp,
+                        PRIZE_VESTING_PERIOD,
+                    )?;
+
+                    emit!(PrizeStreamStartedEvent {
+                        game_id: game.game_id,
+                        recipient: prize_stream.recipient,
+                        amount: prize_stream.total,
+                        start: prize_stream.start,
+                        period: prize_stream.period,
+                    });
+                    prize_stream.exit(&crate::ID)?;
+                } else {
+                    transfer_sol(
+                        chest_vault.to_account_info().clone(),
+                        ctx.accounts.last_bidder.to_account_info(),
+                        winner_prize.safe_sub(prize_fee_amount)?,
+                    )?;
+                }
 
                 emit!(MainPrizeEvent {
                     winner: game.last_bidder,
-                    amount: game.prize_pool_balance,
+                    amount: winner_prize,
                     timestamp: clock.unix_timestamp,
-                    new_round_start_at: game.timer_end + REST_TIME,
+                    new_round_start_at: game.timer_end.safe_add(REST_TIME)?,
                 });
 
                 game.prize_pool_balance = 0;
@@ -642,19 +1590,105 @@ pub mod solana_lastbid_program {
                 winner: ctx.accounts.treasury.key(),
                 amount: game.prize_pool_balance,
                 timestamp: clock.unix_timestamp,
-                new_round_start_at: game.timer_end + REST_TIME,
+                new_round_start_at: game.timer_end.safe_add(REST_TIME)?,
             });
 
             game.prize_pool_balance = 0;
         }
 
+        assert_vault_solvent(
+            &ctx.accounts.chest_vault.to_account_info(),
+            Some(&ctx.accounts.game),
+            &ctx.accounts.vault_ledger,
+        )?;
+
+        Ok(())
+    }
+
+    pub fn audit_vault(ctx: Context<AuditVault>) -> Result<()> {
+        let game = &ctx.accounts.game;
+        let chest_vault = ctx.accounts.chest_vault.to_account_info();
+        let liabilities =
+            VaultLiabilities::new(&chest_vault, Some(game), &ctx.accounts.vault_ledger)?;
+        let vault_balance = chest_vault.lamports();
+
+        emit!(VaultAuditEvent {
+            game_id: game.game_id,
+            vault_balance,
+            prize_pool: liabilities.prize_pool,
+            revenue: liabilities.revenue,
+            referral: liabilities.referral,
+            past_revenue: liabilities.past_revenue,
+            prize_stream: liabilities.prize_stream,
+            airdrop: liabilities.airdrop,
+            next_round_seed: liabilities.next_round_seed,
+            dust: liabilities.dust,
+            rent_exempt: liabilities.rent_exempt,
+            surplus: vault_balance.saturating_sub(liabilities.total()?),
+            deficit: liabilities.total()?.saturating_sub(vault_balance),
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+
         Ok(())
     }
 
-    pub fn claim_revenue(ctx: Context<ClaimRevenue>, group_number: u64) -> Result<()> {
+    pub fn sweep_surplus(ctx: Context<SweepSurplus>) -> Result<()> {
+        let game = &ctx.accounts.game;
+        let ownership = &ctx.accounts.ownership;
+        let chest_vault = ctx.accounts.chest_vault.to_account_info();
+
+        // Verify the ownership
+        ownership.verify_ownership(ctx.accounts.owner.key())?;
+
+        // Verify the treasury wallet
+        require_keys_eq!(
+            ctx.accounts.treasury.key(),
+            game.treasury,
+            BidErrorCode::NotTreasury
+        );
+
+        // Only the lamports above every recorded liability can leave
+        let liabilities =
+            VaultLiabilities::new(&chest_vault, Some(game), &ctx.accounts.vault_ledger)?;
+        let surplus = chest_vault.lamports().saturating_sub(liabilities.total()?);
+        require!(surplus > 0, BidErrorCode::NoSurplus);
+
+        transfer_sol(
+            chest_vault.clone(),
+            ctx.accounts.treasury.to_account_info(),
+            surplus,
+        )?;
+
+        emit!(SurplusSweptEvent {
+            treasury: ctx.accounts.treasury.key(),
+            amount: surplus,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+
+        assert_vault_solvent(
+            &ctx.accounts.chest_vault.to_account_info(),
+            Some(&ctx.accounts.game),
+            &ctx.accounts.vault_ledger,
+        )?;
+
+        Ok(())
+    }
+
+    pub fn claim_revenue(
+        ctx: Context<ClaimRevenue>,
+        game_id: u64,
+        group_number: u64,
+    ) -> Result<()> {
+        // A claim may leave an underfunded vault short, but never shorter
+        let surplus_before = vault_surplus(
+            &ctx.accounts.chest_vault.to_account_info(),
+            Some(&ctx.accounts.game),
+            &ctx.accounts.vault_ledger,
+        )?;
         let game = &mut ctx.accounts.game;
         let key_holders = &mut ctx.accounts.key_holders;
         let group_revenue_data = &mut ctx.accounts.group_revenue_counter;
+        let vault_ledger = &mut ctx.accounts.vault_ledger;
         let clock = Clock::get()?;
 
         // Verify if the game ended
@@ -663,70 +1697,671 @@ pub mod solana_lastbid_program {
         //     BidErrorCode::GameNotEnded
         // );
 
-        require!(game.total_holders > 0, BidErrorCode::NoKeyHolders);
+        // Send payment to the payer and distribute group revenue to each holder based on their keys
+        let position = settle_revenue_claim(
+            game,
+            vault_ledger,
+            key_holders,
+            group_revenue_data,
+            &ctx.accounts.chest_vault.to_account_info(),
+            ctx.accounts.payer.key(),
+            game_id,
+            group_number,
+            0,
+            u64::MAX,
+            REVENUE_CLAIM_WINDOW,
+            clock.unix_timestamp,
+        )?;
+
+        transfer_sol(
+            ctx.accounts.chest_vault.to_account_info().clone(),
+            ctx.accounts.payer.to_account_info(),
+            position.paid,
+        )?;
+
+        emit!(RevenueEvent {
+            key_holder: ctx.accounts.payer.key(),
+            claimed_revenue: position.paid,
+            timestamp: clock.unix_timestamp,
+        });
+
+        if position.paid < position.owed {
+            emit!(ClaimShortfallEvent {
+                claimant: ctx.accounts.payer.key(),
+                owed: position.owed,
+                paid: position.paid,
+                shortfall: position.owed.safe_sub(position.paid)?,
+                timestamp: clock.unix_timestamp,
+            });
+        }
+
+        assert_vault_surplus_kept(
+            &ctx.accounts.chest_vault.to_account_info(),
+            Some(&ctx.accounts.game),
+            &ctx.accounts.vault_ledger,
+            surplus_before,
+        )?;
+
+        Ok(())
+    }
+
+    /// Claims several revenue positions at once, passed as
+    /// (key holder account, group revenue counter) pairs in the remaining accounts
+    pub fn claim_all_revenue<'info>(
+        ctx: Context<'_, '_, 'info, 'info, ClaimAllRevenue<'info>>,
+    ) -> Result<()> {
+        let surplus_before = vault_surplus(
+            &ctx.accounts.chest_vault.to_account_info(),
+            Some(&ctx.accounts.game),
+            &ctx.accounts.vault_ledger,
+        )?;
+        let game = &mut ctx.accounts.game;
+        let vault_ledger = &mut ctx.accounts.vault_ledger;
+        let clock = Clock::get()?;
 
-        // Verify group number
-        let current_group_number = game.total_holders / (MAX_HOLDERS as u64);
         require!(
-            (group_number < current_group_number) || !(game.total_holders == game.total_groups * (MAX_HOLDERS as u64)
-                && group_number == current_group_number),
-            BidErrorCode::IncorrectGroupNumber
+            !ctx.remaining_accounts.is_empty() && ctx.remaining_accounts.len() % 2 == 0,
+            BidErrorCode::IncorrectRevenuePositions
         );
 
-        // Send payment to the payer and distribute group revenue to each holder based on their keys
-        let mut revenue_claimed = false;
-        let group_revenue = group_revenue_data.group_counter[group_number as usize];
-        for holder in key_holders.holders.iter_mut() {
-            if holder.holder != ctx.accounts.payer.key() {
-                let holder_amount = group_revenue * holder.keys;
-                holder.total_earned += holder_amount;
-            } else {
-                require!(
-                    holder.total_earned > 0 && !holder.claimed,
-                    BidErrorCode::NoRevenue
-                );
+        let mut positions = Vec::with_capacity(ctx.remaining_accounts.len() / 2);
+        let mut owed = 0u64;
+        let mut paid = 0u64;
+        for accounts in ctx.remaining_accounts.chunks(2) {
+            require!(
+                accounts[0].is_writable && accounts[1].is_writable,
+                BidErrorCode::IncorrectRevenuePositions
+            );
+            // Deserializing checks the program owns the accounts and their type
+            let mut key_holders = Account::<KeyHolderAccount>::try_from(&accounts[0])?;
+            let mut group_revenue_data = Account::<GroupRevenueCounter>::try_from(&accounts[1])?;
+
+            let game_id = key_holders.game_id;
+            let group_number = key_holders.group_number;
+            require!(
+                !positions.iter().any(|position: &RevenueClaimPosition| {
+                    position.game_id == game_id && position.group_number == group_number
+                }),
+                BidErrorCode::IncorrectRevenuePositions
+            );
+
+            let position = settle_revenue_claim(
+                game,
+                vault_ledger,
+                &mut key_holders,
+                &mut group_revenue_data,
+                &ctx.accounts.chest_vault.to_account_info(),
+                ctx.accounts.payer.key(),
+                game_id,
+                group_number,
+                paid,
+                u64::MAX,
+                REVENUE_CLAIM_WINDOW,
+                clock.unix_timestamp,
+            )?;
 
-                let revenue_amount = holder.total_earned + holder.keys * group_revenue;
-                let amount =
-                    if ctx.accounts.chest_vault.to_account_info().lamports() > revenue_amount {
-                        revenue_amount
-                    } else {
-                        ctx.accounts.chest_vault.to_account_info().lamports()
-                    };
+            // Write back now, a counter is shared by every group of its round
+            key_holders.exit(&crate::ID)?;
+            group_revenue_data.exit(&crate::ID)?;
 
-                game.revenue_earned -= revenue_amount;
-                holder.claimed = true;
-                holder.total_earned = 0;
-                revenue_claimed = true;
+            owed = owed.safe_add(position.owed)?;
+            paid = paid.safe_add(position.paid)?;
+            positions.push(position);
+        }
 
-                transfer_sol(
-                    ctx.accounts.chest_vault.to_account_info().clone(),
-                    ctx.accounts.payer.to_account_info(),
-                    amount,
-                )?;
+        transfer_sol(
+            ctx.accounts.chest_vault.to_account_info().clone(),
+            ctx.accounts.payer.to_account_info(),
+            paid,
+        )?;
 
-                emit!(RevenueEvent {
-                    key_holder: holder.holder,
-                    claimed_revenue: amount,
-                    timestamp: clock.unix_timestamp,
-                });
-            }
+        emit!(RevenueBatchClaimedEvent {
+            key_holder: ctx.accounts.payer.key(),
+            claimed_revenue: paid,
+            shortfall: owed.safe_sub(paid)?,
+            positions,
+            timestamp: clock.unix_timestamp,
+        });
+
+        if paid < owed {
+            emit!(ClaimShortfallEvent {
+                claimant: ctx.accounts.payer.key(),
+                owed,
+                paid,
+                shortfall: owed.safe_sub(paid)?,
+                timestamp: clock.unix_timestamp,
+            });
         }
-        group_revenue_data.group_counter[group_number as usize] = 0;
 
-        require!(revenue_claimed, BidErrorCode::PayerNotInKeyHolders);
+        assert_vault_surplus_kept(
+            &ctx.accounts.chest_vault.to_account_info(),
+            Some(&ctx.accounts.game),
+            &ctx.accounts.vault_ledger,
+            surplus_before,
+        )?;
+
+        Ok(())
+    }
+
+    /// Buys keys of the current round with the unclaimed revenue of the buyer's group
+    pub fn reinvest_revenue<'info>(
+        ctx: Context<'_, '_, 'info, 'info, BuyKeys<'info>>,
+        group_number: u64,
+        suggested_amount: u64,
+        number_of_keys: u64,
+        team: u8,
+        proof: Vec<[u8; 32]>,
+    ) -> Result<()> {
+        let game = &mut ctx.accounts.game;
+        let key_holders = &mut ctx.accounts.key_holders;
+        let buyer_game_data = &mut ctx.accounts.buyer_game_account;
+        let group_revenue_data = &mut ctx.accounts.group_revenue_counter;
+        let vault_ledger = &mut ctx.accounts.vault_ledger;
+        let clock = Clock::get()?;
+
+        // Verify game is active
+        require!(
+            game.active && clock.unix_timestamp < game.timer_end,
+            BidErrorCode::GameEnded
+        );
+
+        // Key token revenue is claimed with claim_key_revenue
+        require!(!KEY_TOKEN_MODE, BidErrorCode::KeyTokenModeEnabled);
+
+        // Verify the purchase isn't made by an unknown program
+        if HUMANS_ONLY {
+            verify_human_purchase(
+                &ctx.accounts.instructions.to_account_info(),
+                APPROVED_INTEGRATORS,
+            )?;
+        }
+
+        // Init buyer game data, verifying the wallet is eligible in gated rounds
+        init_buyer_game_data(game, buyer_game_data, ctx.accounts.buyer.key(), &proof)?;
+
+        // Verify the anti-bot limits of the purchase
+        PURCHASE_LIMITS.verify(game, buyer_game_data, number_of_keys, &clock)?;
+
+        // Verify the buyer already holds keys of the round in this group
+        require!(
+            join_key_holder_group(
+                game,
+                key_holders,
+                group_revenue_data,
+                buyer_game_data,
+                ctx.accounts.buyer.key(),
+                group_number,
+            )?,
+            BidErrorCode::PayerNotInKeyHolders
+        );
+
+        // Verify the treasury wallet
+        require_keys_eq!(
+            ctx.accounts.treasury.key(),
+            game.treasury,
+            BidErrorCode::NotTreasury
+        );
+
+        // Verify the team
+        require!((team as usize) < TEAM_COUNT, BidErrorCode::InvalidTeam);
+        let team_config = PURCHASE_TEAMS[team as usize];
+
+        // Clamp the keys to what the timer can still take
+        let (available_keys, new_end_time) = timer_extension(
+            game.timer_end,
+            clock.unix_timestamp,
+            MAX_TIMER,
+            INC_TIME,
+            number_of_keys,
+        )?;
+
+        // Verify the keys per round cap of the wallet
+        PURCHASE_LIMITS.verify_wallet_cap(buyer_game_data.keys_bought, available_keys)?;
+
+        // Verify BID token balance to burn
+        let bid_token_amount = bid_tokens_to_burn(
+            game,
+            ctx.accounts.bid_token_mint_account.key(),
+            ctx.accounts.buyer_bid_token_account.amount,
+            BID_TOKENS_PER_TX,
+            available_keys,
+        )?;
+
+        // Reinvested purchases have no referral, a sticky referral binding included:
+        // they spend revenue the round already paid out
+        let data: Fees = calculate_fees_and_next_price(
+            BUY_FEE,
+            REFERRAL_SHARE,
+            team_config.pot_share()?,
+            team_config.key_holders_share,
+            available_keys,
+            game.current_price,
+            PRICE_INCREASE_RATE,
+            DIVIDER,
+            false,
+        )?;
+
+        let next_round_amount = team_config.next_round_amount(data.prize_pool_amount)?;
+        let airdrop_amount = mul_div(
+            data.prize_pool_amount.safe_sub(next_round_amount)?,
+            AIRDROP_SHARE,
+            DIVIDER,
+        )?;
+
+        // Rounding leftovers of the fee split are carried to the next round
+        let fee_dust = fee_split_dust(&data, &[0; REFERRAL_TIERS])?;
+
+        let max_amount = mul_div(suggested_amount, DIVIDER.safe_add(PRICE_TOLERANCE)?, DIVIDER)?;
+        require!(
+            max_amount >= data.total_amount,
+            BidErrorCode::InvalidPaymentAmount
+        );
+
+        // Pay with the buyer's revenue, taken out of revenue_earned before the purchase adds its share back
+        let game_id = game.game_id;
+        let position = settle_revenue_claim(
+            game,
+            vault_ledger,
+            key_holders,
+            group_revenue_data,
+            &ctx.accounts.chest_vault.to_account_info(),
+            ctx.accounts.buyer.key(),
+            game_id,
+            group_number,
+            0,
+            data.total_amount,
+            REVENUE_CLAIM_WINDOW,
+            clock.unix_timestamp,
+        )?;
+        require!(
+            position.paid == data.total_amount,
+            BidErrorCode::InsufficientRevenue
+        );
+
+        // The payment already sits in the chest, only the buy fee leaves it
+        transfer_sol(
+            ctx.accounts.chest_vault.to_account_info().clone(),
+            ctx.accounts.treasury.to_account_info(),
+            data.treasury_amount,
+        )?;
+
+        // Burn $BID tokens
+        burn_bid_tokens(
+            ctx.accounts.token_program.to_account_info(),
+            ctx.accounts.bid_token_mint_account.to_account_info(),
+            ctx.accounts.buyer_bid_token_account.to_account_info(),
+            ctx.accounts.buyer.to_account_info(),
+            bid_token_amount,
+        )?;
+
+        // Revenue is shared by weight, equal to the keys unless earlier keys are weighted up
+        let weight = purchase_weight(REVENUE_WEIGHTING, game.total_keys, available_keys)?;
+
+        let revenue_dust = data.key_holders_amount.safe_sub(distribute_purchase_revenue(
+            key_holders,
+            group_revenue_data,
+            data.key_holders_amount,
+            group_number,
+            game.total_weight,
+        )?)?;
+
+        update_key_holders(
+            key_holders,
+            ctx.accounts.buyer.key(),
+            available_keys,
+            clock.unix_timestamp,
+        )?;
+        add_holder_weight(key_holders, ctx.accounts.buyer.key(), weight)?;
+
+        // Update buyer data and game state
+        record_purchase(
+            game,
+            buyer_game_data,
+            &Purchase {
+                buyer: ctx.accounts.buyer.key(),
+                team,
+                keys: available_keys,
+                weight,
+                fees: &data,
+                next_round_amount,
+                airdrop_amount,
+                timer_end: new_end_time,
+            },
+            &clock,
+        )?;
+
+        // adjust revenue of the previous key_holders
+        game.revenue_earned = game
+            .revenue_earned
+            .safe_add(data.key_holders_amount.safe_sub(revenue_dust)?)?;
+        game.dust_carry = game.dust_carry.safe_add(fee_dust.safe_add(revenue_dust)?)?;
+
+        emit!(KeyPurchasedEvent {
+            game_id: game.game_id,
+            buyer: ctx.accounts.buyer.key(),
+            amount: data.total_amount,
+            number_of_keys: available_keys,
+            team,
+            new_price: game.current_price,
+            timer_end: game.timer_end,
+            purchased_at: clock.unix_timestamp,
+        });
+
+        emit!(RevenueReinvestedEvent {
+            game_id: game.game_id,
+            key_holder: ctx.accounts.buyer.key(),
+            amount: data.total_amount,
+            number_of_keys: available_keys,
+            timestamp: clock.unix_timestamp,
+        });
+
+        assert_vault_solvent(
+            &ctx.accounts.chest_vault.to_account_info(),
+            Some(&ctx.accounts.game),
+            &ctx.accounts.vault_ledger,
+        )?;
+
+        Ok(())
+    }
+
+    pub fn view_purchase_weight(
+        ctx: Context<ViewRevenueWeight>,
+        number_of_keys: u64,
+    ) -> Result<PurchaseWeightQuote> {
+        let game = &ctx.accounts.game;
+        let clock = Clock::get()?;
+
+        // Quote the keys a purchase would actually get
+        let number_of_keys = if MAX_KEYS_PER_TX == 0 {
+            number_of_keys
+        } else {
+            std::cmp::min(number_of_keys, MAX_KEYS_PER_TX)
+        };
+        let (number_of_keys, _) = timer_extension(
+            game.timer_end,
+            clock.unix_timestamp,
+            MAX_TIMER,
+            INC_TIME,
+            number_of_keys,
+        )?;
+
+        Ok(PurchaseWeightQuote {
+            game_id: game.game_id,
+            number_of_keys,
+            weight: purchase_weight(REVENUE_WEIGHTING, game.total_keys, number_of_keys)?,
+            total_weight: game.total_weight,
+        })
+    }
+
+    pub fn view_pending_revenue(
+        ctx: Context<ViewPendingRevenue>,
+        holder: Pubkey,
+    ) -> Result<PendingRevenue> {
+        pending_revenue(
+            &ctx.accounts.key_holders,
+            &ctx.accounts.group_revenue_counter,
+            holder,
+        )
+    }
+
+    pub fn create_key_mint(ctx: Context<CreateKeyMint>) -> Result<()> {
+        let game = &ctx.accounts.game;
+        let ownership = &ctx.accounts.ownership;
+        let key_mint_state = &mut ctx.accounts.key_mint_state;
+        let clock = Clock::get()?;
+
+        ownership.verify_ownership(ctx.accounts.owner.key())?;
+        require!(KEY_TOKEN_MODE, BidErrorCode::KeyTokenModeDisabled);
+
+        key_mint_state.game_id = game.game_id;
+        key_mint_state.mint = ctx.accounts.key_mint.key();
+        key_mint_state.revenue_per_key = 0;
+        key_mint_state.total_supply = 0;
+        key_mint_state.bump = ctx.bumps.key_mint_state;
+
+        // Token-2022 resolves the mint state and both checkpoints on every transfer
+        init_key_token_extra_account_metas(
+            &ctx.accounts.extra_account_meta_list.to_account_info(),
+            key_mint_state.key(),
+        )?;
+
+        emit!(KeyMintCreatedEvent {
+            game_id: game.game_id,
+            mint: key_mint_state.mint,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    pub fn init_key_checkpoint(ctx: Context<InitKeyCheckpoint>) -> Result<()> {
+        let key_checkpoint = &mut ctx.accounts.key_checkpoint;
+
+        key_checkpoint.owner = ctx.accounts.checkpoint_owner.key();
+        key_checkpoint.mint = ctx.accounts.key_mint.key();
+
+        Ok(())
+    }
+
+    /// Moves the revenue entitlement along a key token transfer, called by Token-2022
+    #[interface(spl_transfer_hook_interface::execute)]
+    pub fn transfer_hook(ctx: Context<TransferHook>, amount: u64) -> Result<()> {
+        assert_key_token_transferring(&ctx.accounts.source_token.to_account_info())?;
+
+        let key_mint_state = &ctx.accounts.key_mint_state;
+        require_keys_eq!(
+            key_mint_state.mint,
+            ctx.accounts.mint.key(),
+            BidErrorCode::IncorrectKeyMint
+        );
+
+        // Checkpoints follow the owners of the token accounts, which must not change
+        assert_immutable_owner(&ctx.accounts.source_token.to_account_info())?;
+        assert_immutable_owner(&ctx.accounts.destination_token.to_account_info())?;
+
+        // A transfer between token accounts of one wallet leaves its entitlement unchanged
+        let source_checkpoint = &mut ctx.accounts.source_checkpoint;
+        if ctx.accounts.destination_checkpoint.key() == source_checkpoint.key() {
+            return transfer_key_entitlement(source_checkpoint, None, key_mint_state, amount);
+        }
+
+        let destination_info = ctx.accounts.destination_checkpoint.to_account_info();
+        let mut destination_checkpoint = Account::<KeyCheckpoint>::try_from(&destination_info)?;
+        transfer_key_entitlement(
+            source_checkpoint,
+            Some(&mut *destination_checkpoint),
+            key_mint_state,
+            amount,
+        )?;
+        destination_checkpoint.exit(&crate::ID)?;
+
+        Ok(())
+    }
+
+    pub fn claim_key_revenue(ctx: Context<ClaimKeyRevenue>, game_id: u64) -> Result<()> {
+        let surplus_before = vault_surplus(
+            &ctx.accounts.chest_vault.to_account_info(),
+            Some(&ctx.accounts.game),
+            &ctx.accounts.vault_ledger,
+        )?;
+        let game = &mut ctx.accounts.game;
+        let key_checkpoint = &mut ctx.accounts.key_checkpoint;
+        let group_revenue_data = &mut ctx.accounts.group_revenue_counter;
+        let vault_ledger = &mut ctx.accounts.vault_ledger;
+        let clock = Clock::get()?;
+
+        // Verify the counter belongs to the round
+        require!(
+            group_revenue_data.game_id == game_id,
+            BidErrorCode::IncorrectGameId
+        );
+        let current_round = game_id == game.game_id;
+        if !current_round {
+            require!(
+                !revenue_claim_expired(
+                    group_revenue_data.closed_at,
+                    REVENUE_CLAIM_WINDOW,
+                    clock.unix_timestamp,
+                )?,
+                BidErrorCode::RevenueClaimExpired
+            );
+        }
+
+        // Pay what the vault can spare, the rest stays claimable
+        let (revenue_amount, amount) = claim_key_checkpoint(
+            key_checkpoint,
+            &ctx.accounts.key_mint_state,
+            available_vault_balance(
+                &ctx.accounts.chest_vault.to_account_info(),
+                revenue_claim_reserve(vault_ledger, current_round)?,
+            )?,
+        )?;
+
+        debit_revenue_claim(
+            game,
+            vault_ledger,
+            group_revenue_data,
+            current_round,
+            amount,
+        )?;
+
+        transfer_sol(
+            ctx.accounts.chest_vault.to_account_info().clone(),
+            ctx.accounts.owner.to_account_info(),
+            amount,
+        )?;
+
+        emit!(KeyRevenueClaimedEvent {
+            game_id,
+            owner: ctx.accounts.owner.key(),
+            amount,
+            timestamp: clock.unix_timestamp,
+        });
+
+        if amount < revenue_amount {
+            emit!(ClaimShortfallEvent {
+                claimant: ctx.accounts.owner.key(),
+                owed: revenue_amount,
+                paid: amount,
+                shortfall: revenue_amount.safe_sub(amount)?,
+                timestamp: clock.unix_timestamp,
+            });
+        }
+
+        assert_vault_surplus_kept(
+            &ctx.accounts.chest_vault.to_account_info(),
+            Some(&ctx.accounts.game),
+            &ctx.accounts.vault_ledger,
+            surplus_before,
+        )?;
+
+        Ok(())
+    }
+
+    pub fn sweep_expired_revenue(ctx: Context<SweepExpiredRevenue>, game_id: u64) -> Result<()> {
+        let game = &mut ctx.accounts.game;
+        let vault_ledger = &mut ctx.accounts.vault_ledger;
+        let group_revenue_data = &mut ctx.accounts.group_revenue_counter;
+        let clock = Clock::get()?;
+
+        // Verify the claim window of the finished round is over
+        require!(
+            revenue_claim_expired(
+                group_revenue_data.closed_at,
+                REVENUE_CLAIM_WINDOW,
+                clock.unix_timestamp,
+            )?,
+            BidErrorCode::RevenueClaimNotExpired
+        );
+
+        // The unclaimed revenue goes to the prize pool of a future round
+        let amount = group_revenue_data.outstanding;
+        group_revenue_data.outstanding = 0;
+        group_revenue_data.group_counter = vec![];
+        vault_ledger.past_revenue_reserved = vault_ledger.past_revenue_reserved.safe_sub(amount)?;
+        game.next_round_seed = game.next_round_seed.safe_add(amount)?;
+
+        emit!(ExpiredRevenueSweptEvent {
+            game_id,
+            amount,
+            timestamp: clock.unix_timestamp,
+        });
+
+        assert_vault_solvent(
+            &ctx.accounts.chest_vault.to_account_info(),
+            Some(&ctx.accounts.game),
+            &ctx.accounts.vault_ledger,
+        )?;
+
+        Ok(())
+    }
+
+    /// Creates the stream of the current round's main prize, for release_main_prize to start
+    pub fn open_prize_stream(ctx: Context<OpenPrizeStream>, game_id: u64) -> Result<()> {
+        let prize_stream = &mut ctx.accounts.prize_stream;
+
+        prize_stream.game_id = game_id;
+        prize_stream.recipient = Pubkey::default();
+        prize_stream.total = 0;
+        prize_stream.withdrawn = 0;
+        prize_stream.start = 0;
+        prize_stream.period = 0;
+
+        Ok(())
+    }
+
+    /// Pays the winner the part of a streamed main prize vested so far
+    pub fn withdraw_vested(ctx: Context<WithdrawVested>, game_id: u64) -> Result<()> {
+        let surplus_before = vault_surplus(
+            &ctx.accounts.chest_vault.to_account_info(),
+            Some(&ctx.accounts.game),
+            &ctx.accounts.vault_ledger,
+        )?;
+        let prize_stream = &mut ctx.accounts.prize_stream;
+        let vault_ledger = &mut ctx.accounts.vault_ledger;
+        let clock = Clock::get()?;
+
+        let amount = withdraw_prize_stream(prize_stream, vault_ledger, clock.unix_timestamp)?;
+
+        transfer_sol(
+            ctx.accounts.chest_vault.to_account_info().clone(),
+            ctx.accounts.recipient.to_account_info(),
+            amount,
+        )?;
+
+        emit!(PrizeWithdrawnEvent {
+            game_id,
+            recipient: prize_stream.recipient,
+            amount,
+            withdrawn: prize_stream.withdrawn,
+            total: prize_stream.total,
+            timestamp: clock.unix_timestamp,
+        });
+
+        assert_vault_surplus_kept(
+            &ctx.accounts.chest_vault.to_account_info(),
+            Some(&ctx.accounts.game),
+            &ctx.accounts.vault_ledger,
+            surplus_before,
+        )?;
 
         Ok(())
     }
 
     pub fn claim_referral_bonus(ctx: Context<ClaimReferralBonus>, _ref_code: String) -> Result<()> {
+        let surplus_before = vault_surplus(
+            &ctx.accounts.chest_vault.to_account_info(),
+            Some(&ctx.accounts.game),
+            &ctx.accounts.vault_ledger,
+        )?;
         let referral_data = &mut ctx.accounts.referral_account;
-        let game = &mut ctx.accounts.game;
+        let vault_ledger = &mut ctx.accounts.vault_ledger;
         let clock = Clock::get()?;
 
-        // Verify if the referral account actived or the payer is the owner of referral account
+        // Verify the payer is the owner of referral account. Deactivated and disabled codes
+        // can still claim, the bonus was earned before
         require!(
-            referral_data.active && referral_data.owner == ctx.accounts.payer.key(),
+            referral_data.owner == ctx.accounts.payer.key(),
             BidErrorCode::IncorrectReferralData
         );
         require!(
@@ -734,19 +2369,47 @@ pub mod solana_lastbid_program {
             BidErrorCode::NoReferralBonus
         );
 
-        let amount =
-            if ctx.accounts.chest_vault.to_account_info().lamports() > referral_data.total_earned {
-                referral_data.total_earned
-            } else {
-                ctx.accounts.chest_vault.to_account_info().lamports()
-            };
+        // The bonus is reserved in the vault ledger when credited, whatever the round.
+        // If the vault still can't cover it above rent exemption, the rest stays claimable.
+        let owed = referral_data.total_earned;
+        let amount = std::cmp::min(
+            owed,
+            available_vault_balance(
+                &ctx.accounts.chest_vault.to_account_info(),
+                vault_ledger.prize_stream_reserved,
+            )?,
+        );
 
-        game.referral_earned -= referral_data.total_earned;
-        referral_data.total_earned = 0;
+        vault_ledger.referral_reserved = vault_ledger.referral_reserved.safe_sub(amount)?;
+        referral_data.total_earned = referral_data.total_earned.safe_sub(amount)?;
+        let mut left_to_settle = amount;
+        for tier_earned in referral_data.tier_earned.iter_mut() {
+            let tier_paid = std::cmp::min(*tier_earned, left_to_settle);
+            *tier_earned = tier_earned.safe_sub(tier_paid)?;
+            left_to_settle = left_to_settle.safe_sub(tier_paid)?;
+        }
+
+        // Pay the payout wallet of the referral account if one is set
+        let recipient = match referral_data.payout_wallet {
+            Some(payout_wallet) => {
+                let recipient = ctx
+                    .accounts
+                    .payout_wallet
+                    .as_ref()
+                    .ok_or(BidErrorCode::IncorrectPayoutWallet)?;
+                require_keys_eq!(
+                    recipient.key(),
+                    payout_wallet,
+                    BidErrorCode::IncorrectPayoutWallet
+                );
+                recipient.to_account_info()
+            }
+            None => ctx.accounts.payer.to_account_info(),
+        };
 
         transfer_sol(
             ctx.accounts.chest_vault.to_account_info().clone(),
-            ctx.accounts.payer.to_account_info(),
+            recipient,
             amount,
         )?;
 
@@ -756,6 +2419,23 @@ pub mod solana_lastbid_program {
             timestamp: clock.unix_timestamp,
         });
 
+        if amount < owed {
+            emit!(ClaimShortfallEvent {
+                claimant: referral_data.owner,
+                owed,
+                paid: amount,
+                shortfall: owed.safe_sub(amount)?,
+                timestamp: clock.unix_timestamp,
+            });
+        }
+
+        assert_vault_surplus_kept(
+            &ctx.accounts.chest_vault.to_account_info(),
+            Some(&ctx.accounts.game),
+            &ctx.accounts.vault_ledger,
+            surplus_before,
+        )?;
+
         Ok(())
     }
 }
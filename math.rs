@@ -0,0 +1,91 @@
+use anchor_lang::prelude::*;
+
+use crate::BidErrorCode;
+
+/// Checked arithmetic for the money and timer math, failing with `MathOverflow`
+pub trait SafeMath: Sized {
+    fn safe_add(self, other: Self) -> Result<Self>;
+    fn safe_sub(self, other: Self) -> Result<Self>;
+    fn safe_mul(self, other: Self) -> Result<Self>;
+}
+
+macro_rules! impl_safe_math {
+    ($($t:ty),*) => {
+        $(
+            impl SafeMath for $t {
+                fn safe_add(self, other: Self) -> Result<Self> {
+                    Ok(self.checked_add(other).ok_or(BidErrorCode::MathOverflow)?)
+                }
+
+                fn safe_sub(self, other: Self) -> Result<Self> {
+                    Ok(self.checked_sub(other).ok_or(BidErrorCode::MathOverflow)?)
+                }
+
+                fn safe_mul(self, other: Self) -> Result<Self> {
+                    Ok(self.checked_mul(other).ok_or(BidErrorCode::MathOverflow)?)
+                }
+            }
+        )*
+    };
+}
+
+impl_safe_math!(u64, i64, u128);
+
+/// `amount * numerator / denominator`, widened to u128 so the product can't overflow
+pub fn mul_div(amount: u64, numerator: u64, denominator: u64) -> Result<u64> {
+    let result = (amount as u128)
+        .safe_mul(numerator as u128)?
+        .checked_div(denominator as u128)
+        .ok_or(BidErrorCode::MathOverflow)?;
+
+    Ok(u64::try_from(result).map_err(|_| BidErrorCode::MathOverflow)?)
+}
+
+/// `amount * numerator / denominator` rounded up, widened to u128 like `mul_div`
+pub fn mul_div_up(amount: u64, numerator: u64, denominator: u64) -> Result<u64> {
+    require!(denominator > 0, BidErrorCode::MathOverflow);
+    let product = (amount as u128).safe_mul(numerator as u128)?;
+    let result = product
+        .safe_add(denominator as u128 - 1)?
+        .checked_div(denominator as u128)
+        .ok_or(BidErrorCode::MathOverflow)?;
+
+    Ok(u64::try_from(result).map_err(|_| BidErrorCode::MathOverflow)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overflow_is_an_error() {
+        assert!(u64::MAX.safe_add(1).is_err());
+        assert!(u64::MAX.safe_mul(2).is_err());
+        assert!(0u64.safe_sub(1).is_err());
+        assert!(i64::MAX.safe_add(1).is_err());
+        assert!(i64::MIN.safe_sub(1).is_err());
+        assert_eq!(u64::MAX.safe_sub(u64::MAX).unwrap(), 0);
+        assert_eq!(u64::MAX.safe_mul(1).unwrap(), u64::MAX);
+    }
+
+    #[test]
+    fn mul_div_widens_the_product() {
+        assert_eq!(mul_div(u64::MAX, u64::MAX, u64::MAX).unwrap(), u64::MAX);
+        assert_eq!(
+            mul_div(u64::MAX, 9_000, 10_000).unwrap(),
+            16_602_069_666_338_596_453
+        );
+        // the quotient itself must fit in a u64
+        assert!(mul_div(u64::MAX, 2, 1).is_err());
+        assert!(mul_div(1, 1, 0).is_err());
+    }
+
+    #[test]
+    fn mul_div_up_rounds_up() {
+        assert_eq!(mul_div_up(10, 1, 3).unwrap(), 4);
+        assert_eq!(mul_div_up(9, 1, 3).unwrap(), 3);
+        assert_eq!(mul_div_up(u64::MAX, u64::MAX, u64::MAX).unwrap(), u64::MAX);
+        assert!(mul_div_up(u64::MAX, 2, 1).is_err());
+        assert!(mul_div_up(1, 1, 0).is_err());
+    }
+}
@@ -0,0 +1,250 @@
+use anchor_lang::prelude::*;
+
+use crate::{mul_div, BidErrorCode, Game, SafeMath, VaultLedger, VAULT_LEDGER_SEED};
+
+pub const PRIZE_STREAM_SEED: &[u8] = b"prize_stream";
+
+/// Main prize vesting linearly to the winner, seeded by [PRIZE_STREAM_SEED, game_id].
+/// Reserved in the vault ledger, so it keeps paying out after the game is reinitialized.
+#[account]
+#[derive(InitSpace)]
+pub struct PrizeStream {
+    pub game_id: u64,
+    pub recipient: Pubkey,
+    pub total: u64,
+    pub withdrawn: u64,
+    pub start: i64,
+    pub period: i64,
+}
+
+impl PrizeStream {
+    /// Part of the prize vested at `now` and not withdrawn yet
+    pub fn withdrawable(&self, now: i64) -> Result<u64> {
+        let elapsed = std::cmp::min(std::cmp::max(now.safe_sub(self.start)?, 0), self.period);
+        let vested = if self.period == 0 {
+            self.total
+        } else {
+            mul_div(self.total, elapsed as u64, self.period as u64)?
+        };
+
+        vested.safe_sub(self.withdrawn)
+    }
+}
+
+/// Loads the stream of a round opened by open_prize_stream, passed to release_main_prize
+/// as the first remaining account. The stream must not be started yet.
+pub fn load_prize_stream<'info>(
+    prize_stream_info: &'info AccountInfo<'info>,
+    game_id: u64,
+) -> Result<Account<'info, PrizeStream>> {
+    let (address, _) =
+        Pubkey::find_program_address(&[PRIZE_STREAM_SEED, &game_id.to_le_bytes()], &crate::ID);
+    require_keys_eq!(
+        prize_stream_info.key(),
+        address,
+        BidErrorCode::MissingPrizeStream
+    );
+    require!(
+        prize_stream_info.is_writable,
+        BidErrorCode::MissingPrizeStream
+    );
+
+    let prize_stream = Account::<PrizeStream>::try_from(prize_stream_info)?;
+    require!(
+        prize_stream.game_id == game_id && prize_stream.recipient == Pubkey::default(),
+        BidErrorCode::MissingPrizeStream
+    );
+
+    Ok(prize_stream)
+}
+
+/// Starts the stream of a main prize and reserves it in the vault ledger
+pub fn start_prize_stream(
+    prize_stream: &mut PrizeStream,
+    vault_ledger: &mut VaultLedger,
+    game_id: u64,
+    recipient: Pubkey,
+    amount: u64,
+    start: i64,
+    period: i64,
+) -> Result<()> {
+    require!(period > 0, BidErrorCode::InvalidVestingPeriod);
+
+    prize_stream.game_id = game_id;
+    prize_stream.recipient = recipient;
+    prize_stream.total = amount;
+    prize_stream.withdrawn = 0;
+    prize_stream.start = start;
+    prize_stream.period = period;
+    vault_ledger.prize_stream_reserved = vault_ledger.prize_stream_reserved.safe_add(amount)?;
+
+    Ok(())
+}
+
+/// Takes the part of the prize vested at `now` out of the stream and its vault ledger reserve.
+/// Returns the amount to pay the recipient.
+pub fn withdraw_prize_stream(
+    prize_stream: &mut PrizeStream,
+    vault_ledger: &mut VaultLedger,
+    now: i64,
+) -> Result<u64> {
+    let amount = prize_stream.withdrawable(now)?;
+    require!(amount > 0, BidErrorCode::NothingVested);
+
+    prize_stream.withdrawn = prize_stream.withdrawn.safe_add(amount)?;
+    vault_ledger.prize_stream_reserved = vault_ledger.prize_stream_reserved.safe_sub(amount)?;
+
+    Ok(amount)
+}
+
+#[derive(Accounts)]
+#[instruction(game_id: u64)]
+pub struct OpenPrizeStream<'info> {
+    #[account(
+        address = vault_ledger.game @ BidErrorCode::IncorrectGameId,
+        constraint = game.game_id == game_id @ BidErrorCode::IncorrectGameId,
+    )]
+    pub game: Account<'info, Game>,
+
+    #[account(seeds = [VAULT_LEDGER_SEED], bump)]
+    pub vault_ledger: Account<'info, VaultLedger>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + PrizeStream::INIT_SPACE,
+        seeds = [PRIZE_STREAM_SEED, &game_id.to_le_bytes()],
+        bump,
+    )]
+    pub prize_stream: Account<'info, PrizeStream>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(game_id: u64)]
+pub struct WithdrawVested<'info> {
+    #[account(address = vault_ledger.game @ BidErrorCode::IncorrectGameId)]
+    pub game: Account<'info, Game>,
+
+    #[account(
+        mut,
+        seeds = [PRIZE_STREAM_SEED, &game_id.to_le_bytes()],
+        bump,
+        has_one = recipient @ BidErrorCode::NotPrizeRecipient,
+    )]
+    pub prize_stream: Account<'info, PrizeStream>,
+
+    #[account(mut, seeds = [VAULT_LEDGER_SEED], bump)]
+    pub vault_ledger: Account<'info, VaultLedger>,
+
+    /// CHECK: the chest vault recorded in the ledger, only lamports are moved out of it
+    #[account(mut, address = vault_ledger.chest_vault @ BidErrorCode::IncorrectChestVault)]
+    pub chest_vault: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub recipient: Signer<'info>,
+}
+
+#[event]
+pub struct PrizeStreamStartedEvent {
+    pub game_id: u64,
+    pub recipient: Pubkey,
+    pub amount: u64,
+    pub start: i64,
+    pub period: i64,
+}
+
+#[event]
+pub struct PrizeWithdrawnEvent {
+    pub game_id: u64,
+    pub recipient: Pubkey,
+    pub amount: u64,
+    pub withdrawn: u64,
+    pub total: u64,
+    pub timestamp: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOL: u64 = 1_000_000_000;
+    const PERIOD: i64 = 1_000;
+
+    fn vault_ledger() -> VaultLedger {
+        VaultLedger {
+            referral_reserved: 0,
+            past_revenue_reserved: 0,
+            prize_stream_reserved: 0,
+            game: Pubkey::new_unique(),
+            chest_vault: Pubkey::new_unique(),
+        }
+    }
+
+    fn opened_stream(game_id: u64) -> PrizeStream {
+        PrizeStream {
+            game_id,
+            recipient: Pubkey::default(),
+            total: 0,
+            withdrawn: 0,
+            start: 0,
+            period: 0,
+        }
+    }
+
+    #[test]
+    fn stream_vests_linearly() {
+        let mut ledger = vault_ledger();
+        let mut stream = opened_stream(1);
+        let winner = Pubkey::new_unique();
+
+        start_prize_stream(&mut stream, &mut ledger, 1, winner, SOL, 100, PERIOD).unwrap();
+        assert_eq!(ledger.prize_stream_reserved, SOL);
+
+        assert_eq!(stream.withdrawable(0).unwrap(), 0);
+        assert_eq!(stream.withdrawable(100 + PERIOD / 4).unwrap(), SOL / 4);
+        assert_eq!(stream.withdrawable(100 + PERIOD * 10).unwrap(), SOL);
+        assert!(start_prize_stream(&mut stream, &mut ledger, 1, winner, SOL, 100, 0).is_err());
+    }
+
+    #[test]
+    fn stream_keeps_paying_after_the_next_round_starts() {
+        let mut ledger = vault_ledger();
+        let mut stream = opened_stream(1);
+        let game = ledger.game;
+        let chest_vault = ledger.chest_vault;
+
+        start_prize_stream(
+            &mut stream,
+            &mut ledger,
+            1,
+            Pubkey::new_unique(),
+            SOL,
+            0,
+            PERIOD,
+        )
+        .unwrap();
+        assert_eq!(
+            withdraw_prize_stream(&mut stream, &mut ledger, PERIOD / 2).unwrap(),
+            SOL / 2
+        );
+
+        // initialize_game binds the same ledger again and reserves the round's unclaimed revenue,
+        // the stream reserve is left to the stream
+        crate::bind_vault_ledger(&mut ledger, game, chest_vault).unwrap();
+        ledger.past_revenue_reserved += 5;
+        assert_eq!(ledger.prize_stream_reserved, SOL / 2);
+
+        assert_eq!(
+            withdraw_prize_stream(&mut stream, &mut ledger, PERIOD * 2).unwrap(),
+            SOL / 2
+        );
+        assert_eq!(ledger.prize_stream_reserved, 0);
+        assert_eq!(stream.withdrawn, SOL);
+        assert!(withdraw_prize_stream(&mut stream, &mut ledger, PERIOD * 3).is_err());
+    }
+}
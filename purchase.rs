@@ -0,0 +1,416 @@
+use anchor_lang::prelude::*;
+use anchor_spl::token;
+
+use crate::{
+    check_if_buyer_is_in_the_group, eligibility_leaf, verify_merkle_proof, BidErrorCode,
+    BuyerGameAccount, Fees, Game, GroupRevenueCounter, KeyHolderAccount, SafeMath, MAX_HOLDERS,
+};
+
+/// Anti-bot limits shared by every way of buying keys, zero or false disabling a limit
+#[derive(Clone, Copy)]
+pub struct PurchaseLimits {
+    pub max_keys_per_tx: u64,
+    pub max_keys_per_round_per_wallet: u64,
+    pub cooldown: i64,
+    pub cooldown_slots: u64,
+    pub one_purchase_per_slot: bool,
+}
+
+impl PurchaseLimits {
+    /// Verifies the keys per transaction cap, the cooldowns of the wallet and the one purchase per slot rule
+    pub fn verify(
+        &self,
+        game: &Game,
+        buyer_game_data: &BuyerGameAccount,
+        number_of_keys: u64,
+        clock: &Clock,
+    ) -> Result<()> {
+        // Verify the keys per transaction cap
+        require!(
+            self.max_keys_per_tx == 0 || number_of_keys <= self.max_keys_per_tx,
+            BidErrorCode::TooManyKeysPerTransaction
+        );
+
+        self.verify_cooldown(
+            buyer_game_data.last_purchase_time,
+            buyer_game_data.last_purchase_slot,
+            clock,
+        )?;
+        self.verify_slot(game.last_purchase_time, game.last_purchase_slot, clock)
+    }
+
+    /// Verifies the cooldowns, in seconds and in slots, since the wallet's last purchase
+    pub fn verify_cooldown(
+        &self,
+        last_purchase_time: i64,
+        last_purchase_slot: u64,
+        clock: &Clock,
+    ) -> Result<()> {
+        require!(
+            self.cooldown == 0
+                || last_purchase_time.safe_add(self.cooldown)? <= clock.unix_timestamp,
+            BidErrorCode::PurchaseCooldown
+        );
+        require!(
+            self.cooldown_slots == 0
+                || last_purchase_slot.safe_add(self.cooldown_slots)? <= clock.slot,
+            BidErrorCode::PurchaseCooldown
+        );
+
+        Ok(())
+    }
+
+    /// Verifies no other purchase of the round landed in this slot, given the round's last one
+    pub fn verify_slot(
+        &self,
+        last_purchase_time: i64,
+        last_purchase_slot: u64,
+        clock: &Clock,
+    ) -> Result<()> {
+        require!(
+            !self.one_purchase_per_slot
+                || last_purchase_time == 0
+                || last_purchase_slot != clock.slot,
+            BidErrorCode::SameSlotPurchase
+        );
+
+        Ok(())
+    }
+
+    /// Verifies the keys per round cap of the wallet, once the purchase is clamped by the timer
+    pub fn verify_wallet_cap(&self, keys_bought: u64, keys: u64) -> Result<()> {
+        require!(
+            self.max_keys_per_round_per_wallet == 0
+                || keys_bought.safe_add(keys)? <= self.max_keys_per_round_per_wallet,
+            BidErrorCode::WalletKeyCapExceeded
+        );
+
+        Ok(())
+    }
+}
+
+/// Starts the buyer data of a new round, and verifies the wallet is eligible in gated rounds.
+/// The proof is only needed on the first purchase of the round.
+pub fn init_buyer_game_data(
+    game: &Game,
+    buyer_game_data: &mut BuyerGameAccount,
+    buyer: Pubkey,
+    proof: &[[u8; 32]],
+) -> Result<()> {
+    if buyer_game_data.game_id != game.game_id {
+        buyer_game_data.game_id = game.game_id;
+        buyer_game_data.first_time_buying = true;
+        buyer_game_data.keys_bought = 0;
+    }
+
+    if let Some(eligibility_root) = game.eligibility_root {
+        if buyer_game_data.eligible_game_id != game.game_id {
+            require!(
+                verify_merkle_proof(proof, eligibility_root, eligibility_leaf(&buyer)),
+                BidErrorCode::NotEligible
+            );
+            buyer_game_data.eligible_game_id = game.game_id;
+        }
+    }
+
+    Ok(())
+}
+
+/// Verifies the group the buyer joins or already holds keys in, opening it when it's the first
+/// purchase of a new group. Returns whether the buyer already holds keys in the group.
+pub fn join_key_holder_group(
+    game: &mut Game,
+    key_holders: &mut KeyHolderAccount,
+    group_revenue_data: &mut GroupRevenueCounter,
+    buyer_game_data: &BuyerGameAccount,
+    buyer: Pubkey,
+    group_number: u64,
+) -> Result<bool> {
+    // Verify the key holder account is the group of the round the purchase names
+    require!(
+        key_holders.game_id == game.game_id && group_revenue_data.game_id == game.game_id,
+        BidErrorCode::IncorrectGameId
+    );
+    require!(
+        key_holders.group_number == group_number,
+        BidErrorCode::IncorrectGroupNumber
+    );
+
+    // Verify group number
+    let current_group_number = game.total_holders / (MAX_HOLDERS as u64);
+    require!(
+        group_number <= current_group_number,
+        BidErrorCode::IncorrectGroupNumber
+    );
+
+    if game.total_holders == game.total_groups.safe_mul(MAX_HOLDERS as u64)? {
+        // if the buyer is the first person to buy keys in this group, then init the key_holders data
+        if current_group_number == group_number {
+            group_revenue_data.group_counter.push(0);
+            key_holders.holders = vec![];
+            game.total_groups = game.total_groups.safe_add(1)?;
+        }
+    }
+
+    let already_exist = check_if_buyer_is_in_the_group(key_holders, buyer)?;
+    require!(
+        (group_number < current_group_number && already_exist)
+            || !(group_number == current_group_number
+                && !already_exist
+                && !buyer_game_data.first_time_buying),
+        BidErrorCode::IncorrectGroupNumber
+    );
+
+    Ok(already_exist)
+}
+
+/// Keys of a purchase the timer can still take, and the timer end once they are bought.
+/// Each key adds `inc_time` to the timer, which can't run more than `max_timer` ahead of `now`.
+pub fn timer_extension(
+    timer_end: i64,
+    now: i64,
+    max_timer: i64,
+    inc_time: i64,
+    number_of_keys: u64,
+) -> Result<(u64, i64)> {
+    let max_timer_end = now.safe_add(max_timer)?;
+    let extensible_time = if max_timer_end >= timer_end {
+        max_timer_end.safe_sub(timer_end)?
+    } else {
+        0
+    };
+    let available_keys = std::cmp::min(number_of_keys, (extensible_time / inc_time) as u64);
+    let new_end_time = timer_end.safe_add(inc_time.safe_mul(available_keys as i64)?)?;
+
+    Ok((available_keys, new_end_time))
+}
+
+/// Verifies the buyer holds the $BID of the game to burn for `keys` keys, and returns the amount
+pub fn bid_tokens_to_burn(
+    game: &Game,
+    bid_token_mint: Pubkey,
+    bid_token_balance: u64,
+    tokens_per_key: u64,
+    keys: u64,
+) -> Result<u64> {
+    require!(
+        game.bid_token_mint == bid_token_mint,
+        BidErrorCode::IncorrectBidToken
+    );
+    let bid_token_amount = tokens_per_key.safe_mul(keys)?;
+    require!(
+        bid_token_balance >= bid_token_amount,
+        BidErrorCode::InsufficientBidTokens
+    );
+
+    Ok(bid_token_amount)
+}
+
+/// Burns the $BID paid for a purchase
+pub fn burn_bid_tokens<'info>(
+    token_program: AccountInfo<'info>,
+    mint: AccountInfo<'info>,
+    from: AccountInfo<'info>,
+    authority: AccountInfo<'info>,
+    amount: u64,
+) -> Result<()> {
+    token::burn(
+        CpiContext::new(
+            token_program,
+            token::Burn {
+                mint,
+                from,
+                authority,
+            },
+        ),
+        amount,
+    )
+}
+
+/// A purchase once its keys, weight and fee split are known
+pub struct Purchase<'a> {
+    pub buyer: Pubkey,
+    pub team: u8,
+    pub keys: u64,
+    pub weight: u64,
+    pub fees: &'a Fees,
+    pub next_round_amount: u64,
+    pub airdrop_amount: u64,
+    pub timer_end: i64,
+}
+
+/// Records a purchase in the buyer data and the game state, the buyer becoming the last bidder.
+/// Returns whether it's the buyer's first purchase of the round.
+pub fn record_purchase(
+    game: &mut Game,
+    buyer_game_data: &mut BuyerGameAccount,
+    purchase: &Purchase,
+    clock: &Clock,
+) -> Result<bool> {
+    let new_buyer = buyer_game_data.first_time_buying;
+    if buyer_game_data.first_time_buying {
+        game.total_holders = game.total_holders.safe_add(1)?;
+        buyer_game_data.first_time_buying = false;
+    }
+    buyer_game_data.keys_bought = buyer_game_data.keys_bought.safe_add(purchase.keys)?;
+    buyer_game_data.last_purchase_time = clock.unix_timestamp;
+    buyer_game_data.last_purchase_slot = clock.slot;
+
+    let team = purchase.team as usize;
+    game.last_bidder = purchase.buyer;
+    game.total_keys = game.total_keys.safe_add(purchase.keys)?;
+    game.total_weight = game.total_weight.safe_add(purchase.weight)?;
+    game.total_amount = game.total_amount.safe_add(purchase.fees.total_amount)?;
+    game.current_price = purchase.fees.next_key_price;
+    game.prize_pool_balance = game.prize_pool_balance.safe_add(
+        purchase
+            .fees
+            .prize_pool_amount
+            .safe_sub(purchase.next_round_amount)?
+            .safe_sub(purchase.airdrop_amount)?,
+    )?;
+    game.next_round_seed = game.next_round_seed.safe_add(purchase.next_round_amount)?;
+    game.airdrop_pot = game.airdrop_pot.safe_add(purchase.airdrop_amount)?;
+    game.last_bidder_team = purchase.team;
+    game.team_keys[team] = game.team_keys[team].safe_add(purchase.keys)?;
+    game.team_amounts[team] = game.team_amounts[team].safe_add(purchase.fees.total_amount)?;
+    game.last_purchase_time = clock.unix_timestamp;
+    game.last_purchase_slot = clock.slot;
+    game.timer_end = purchase.timer_end;
+
+    Ok(new_buyer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_TIMER: i64 = 86_400;
+    const INC_TIME: i64 = 30;
+
+    #[test]
+    fn keys_are_clamped_to_the_max_timer() {
+        let now = 1_000_000;
+
+        // u64::MAX keys only get what the timer can still take
+        let (keys, timer_end) = timer_extension(now, now, MAX_TIMER, INC_TIME, u64::MAX).unwrap();
+        assert_eq!(keys, (MAX_TIMER / INC_TIME) as u64);
+        assert_eq!(timer_end, now + MAX_TIMER);
+
+        let (keys, timer_end) = timer_extension(now, now, MAX_TIMER, INC_TIME, 3).unwrap();
+        assert_eq!(keys, 3);
+        assert_eq!(timer_end, now + 3 * INC_TIME);
+    }
+
+    #[test]
+    fn saturated_timer_takes_no_keys() {
+        let now = 1_000_000;
+
+        for timer_end in [now + MAX_TIMER, now + MAX_TIMER + 1, i64::MAX] {
+            let (keys, new_end) =
+                timer_extension(timer_end, now, MAX_TIMER, INC_TIME, u64::MAX).unwrap();
+            assert_eq!(keys, 0);
+            assert_eq!(new_end, timer_end);
+        }
+    }
+
+    #[test]
+    fn timer_overflow_is_an_error() {
+        assert!(timer_extension(0, i64::MAX, MAX_TIMER, INC_TIME, 1).is_err());
+    }
+
+    const NO_LIMITS: PurchaseLimits = PurchaseLimits {
+        max_keys_per_tx: 0,
+        max_keys_per_round_per_wallet: 0,
+        cooldown: 0,
+        cooldown_slots: 0,
+        one_purchase_per_slot: false,
+    };
+
+    fn clock(unix_timestamp: i64, slot: u64) -> Clock {
+        Clock {
+            slot,
+            unix_timestamp,
+            ..Clock::default()
+        }
+    }
+
+    #[test]
+    fn cooldown_in_seconds_since_the_last_purchase() {
+        let limits = PurchaseLimits {
+            cooldown: 60,
+            ..NO_LIMITS
+        };
+
+        assert!(limits
+            .verify_cooldown(1_000, 50, &clock(1_059, 500))
+            .is_err());
+        assert!(limits
+            .verify_cooldown(1_000, 50, &clock(1_060, 500))
+            .is_ok());
+        // a first purchase is never cooling down
+        assert!(limits.verify_cooldown(0, 0, &clock(1_000, 1)).is_ok());
+        assert!(limits
+            .verify_cooldown(i64::MAX, 0, &clock(i64::MAX, 1))
+            .is_err());
+    }
+
+    #[test]
+    fn cooldown_in_slots_since_the_last_purchase() {
+        let limits = PurchaseLimits {
+            cooldown_slots: 4,
+            ..NO_LIMITS
+        };
+
+        assert!(limits
+            .verify_cooldown(1_000, 50, &clock(2_000, 53))
+            .is_err());
+        assert!(limits.verify_cooldown(1_000, 50, &clock(2_000, 54)).is_ok());
+        // both cooldowns have to be over
+        let both = PurchaseLimits {
+            cooldown: 60,
+            ..limits
+        };
+        assert!(both.verify_cooldown(1_000, 50, &clock(1_030, 60)).is_err());
+        assert!(both.verify_cooldown(1_000, 50, &clock(2_000, 52)).is_err());
+        assert!(NO_LIMITS
+            .verify_cooldown(1_000, 50, &clock(1_000, 50))
+            .is_ok());
+    }
+
+    #[test]
+    fn one_purchase_per_slot() {
+        let limits = PurchaseLimits {
+            one_purchase_per_slot: true,
+            ..NO_LIMITS
+        };
+
+        assert!(limits.verify_slot(1_000, 50, &clock(1_000, 50)).is_err());
+        assert!(limits.verify_slot(1_000, 50, &clock(1_000, 51)).is_ok());
+        // the first purchase of a round, before any slot is recorded
+        assert!(limits.verify_slot(0, 0, &clock(1_000, 0)).is_ok());
+        assert!(NO_LIMITS.verify_slot(1_000, 50, &clock(1_000, 50)).is_ok());
+    }
+
+    #[test]
+    fn wallet_cap_overflow_is_an_error() {
+        let limits = PurchaseLimits {
+            max_keys_per_tx: 0,
+            max_keys_per_round_per_wallet: 100,
+            cooldown: 0,
+            cooldown_slots: 0,
+            one_purchase_per_slot: false,
+        };
+
+        assert!(limits.verify_wallet_cap(1, u64::MAX).is_err());
+        assert!(limits.verify_wallet_cap(99, 2).is_err());
+        assert!(limits.verify_wallet_cap(99, 1).is_ok());
+
+        // a disabled cap takes any amount of keys
+        let uncapped = PurchaseLimits {
+            max_keys_per_round_per_wallet: 0,
+            ..limits
+        };
+        assert!(uncapped.verify_wallet_cap(u64::MAX, u64::MAX).is_ok());
+    }
+}
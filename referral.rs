@@ -0,0 +1,994 @@
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::keccak;
+
+use crate::{mul_div, BidErrorCode, Fees, Game, Ownership, ReferralAccount, SafeMath, VaultLedger};
+
+/// Direct referrer, its referrer, and the one above
+pub const REFERRAL_TIERS: usize = 3;
+
+pub const REFERRAL_CODE_SEED: &[u8] = b"referral_code";
+pub const REFERRAL_GAME_STATS_SEED: &[u8] = b"referral_game_stats";
+pub const MIN_REF_CODE_LEN: usize = 3;
+pub const MAX_REF_CODE_LEN: usize = 16;
+pub const RESERVED_REF_CODES: &[&str] = &[
+    "admin", "lastbid", "official", "owner", "support", "system", "team", "treasury",
+];
+
+/// Codes are case-insensitive, they are stored and compared lowercased
+pub fn normalize_ref_code(ref_code: &str) -> String {
+    ref_code.to_ascii_lowercase()
+}
+
+/// Seed of the `ReferralCode` PDA. The normalized code is hashed so any input
+/// reaches `validate_ref_code` instead of failing on the seed length.
+pub fn ref_code_seed(ref_code: &str) -> [u8; 32] {
+    keccak::hash(normalize_ref_code(ref_code).as_bytes()).0
+}
+
+/// Verifies a normalized code: 3 to 16 characters among a-z, 0-9, '_' and '-', and not reserved
+pub fn validate_ref_code(ref_code: &str) -> Result<()> {
+    require!(
+        ref_code.len() >= MIN_REF_CODE_LEN,
+        BidErrorCode::ReferralCodeTooShort
+    );
+    require!(
+        ref_code.len() <= MAX_REF_CODE_LEN,
+        BidErrorCode::ReferralCodeTooLong
+    );
+    require!(
+        ref_code
+            .bytes()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == b'_' || c == b'-'),
+        BidErrorCode::ReferralCodeInvalidCharacter
+    );
+    require!(
+        !RESERVED_REF_CODES.contains(&ref_code),
+        BidErrorCode::ReferralCodeReserved
+    );
+
+    Ok(())
+}
+
+/// Referral share of the fee once a referrer's cumulative referred volume reaches `min_volume`
+#[derive(Clone, Copy)]
+pub struct ReferralVolumeTier {
+    pub min_volume: u64,
+    pub share: u64,
+}
+
+/// Index of the highest tier reached by `referred_volume`, tiers being sorted by `min_volume`
+pub fn referral_volume_tier(tiers: &[ReferralVolumeTier], referred_volume: u64) -> usize {
+    tiers
+        .iter()
+        .rposition(|tier| referred_volume >= tier.min_volume)
+        .unwrap_or(0)
+}
+
+/// Tier `referred_volume` moves a referrer of `current_tier` up to, if any.
+/// A referrer never moves down.
+pub fn referral_tier_upgrade(
+    tiers: &[ReferralVolumeTier],
+    current_tier: u8,
+    referred_volume: u64,
+) -> Option<u8> {
+    let tier = referral_volume_tier(tiers, referred_volume) as u8;
+    (tier > current_tier).then_some(tier)
+}
+
+/// Adds a purchase to the referred volume and returns the new tier if the referrer moved up
+pub fn record_referred_volume(
+    referral_data: &mut ReferralAccount,
+    tiers: &[ReferralVolumeTier],
+    amount: u64,
+) -> Result<Option<u8>> {
+    referral_data.referred_volume = referral_data.referred_volume.safe_add(amount)?;
+
+    let upgrade = referral_tier_upgrade(tiers, referral_data.tier, referral_data.referred_volume);
+    if let Some(tier) = upgrade {
+        referral_data.tier = tier;
+    }
+
+    Ok(upgrade)
+}
+
+/// Part of the fees given back to a buyer purchasing with a referral
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum RebateSource {
+    ReferralShare,
+    TreasuryFee,
+}
+
+/// Split of a referral purchase's fees once the buyer rebate is taken out
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReferralRebate {
+    pub referral_amount: u64,
+    pub treasury_amount: u64,
+    pub rebate_amount: u64,
+}
+
+/// Takes the buyer rebate out of the referral share or the treasury fee, per `source`
+pub fn referral_rebate(
+    referral_amount: u64,
+    treasury_amount: u64,
+    rebate_share: u64,
+    source: RebateSource,
+    divider: u64,
+) -> Result<ReferralRebate> {
+    match source {
+        RebateSource::ReferralShare => {
+            let rebate_amount = mul_div(referral_amount, rebate_share, divider)?;
+            Ok(ReferralRebate {
+                referral_amount: referral_amount.safe_sub(rebate_amount)?,
+                treasury_amount,
+                rebate_amount,
+            })
+        }
+        RebateSource::TreasuryFee => {
+            let rebate_amount = mul_div(treasury_amount, rebate_share, divider)?;
+            Ok(ReferralRebate {
+                referral_amount,
+                treasury_amount: treasury_amount.safe_sub(rebate_amount)?,
+                rebate_amount,
+            })
+        }
+    }
+}
+
+/// Takes the buyer rebate of a referral purchase out of `source` and lowers the amount to pay
+pub fn apply_referral_rebate(
+    data: &mut Fees,
+    rebate_share: u64,
+    source: RebateSource,
+    divider: u64,
+) -> Result<()> {
+    let rebate = referral_rebate(
+        data.referral_amount,
+        data.treasury_amount,
+        rebate_share,
+        source,
+        divider,
+    )?;
+
+    data.referral_amount = rebate.referral_amount;
+    data.treasury_amount = rebate.treasury_amount;
+    data.rebate_amount = rebate.rebate_amount;
+    data.total_amount = data.total_amount.safe_sub(rebate.rebate_amount)?;
+
+    Ok(())
+}
+
+/// Whether the first referral account a wallet buys with sticks to it
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum StickyReferral {
+    Off,
+    Round,
+    Permanent,
+}
+
+/// Whether a referral account can be credited: not paused by its owner nor disabled by the admin.
+/// The two flags are kept apart so lifting one doesn't override the other.
+pub fn referral_enabled(referral_data: &ReferralAccount) -> bool {
+    referral_data.active && !referral_data.admin_disabled
+}
+
+/// Referral account the buyer is bound to under `mode`, if any
+pub fn bound_referral(
+    mode: StickyReferral,
+    bound_referral: Option<Pubkey>,
+    bound_referral_game_id: u64,
+    game_id: u64,
+) -> Option<Pubkey> {
+    match mode {
+        StickyReferral::Off => None,
+        StickyReferral::Round if bound_referral_game_id != game_id => None,
+        StickyReferral::Round | StickyReferral::Permanent => bound_referral,
+    }
+}
+
+/// Whether the referral a buyer is bound to can still be credited, read from the account passed
+/// at its address. A closed or deactivated referral no longer holds the buyer.
+pub fn bound_referral_enabled(account: &AccountInfo, bound_referral: Pubkey) -> Result<bool> {
+    require_keys_eq!(
+        account.key(),
+        bound_referral,
+        BidErrorCode::IncorrectReferralData
+    );
+    if account.owner != &crate::ID || account.data_is_empty() {
+        return Ok(false);
+    }
+
+    let referral_data = ReferralAccount::try_deserialize(&mut &account.try_borrow_data()?[..])?;
+    Ok(referral_enabled(&referral_data))
+}
+
+/// Binding to record after a purchase with the referral account `used`, None to keep the current one.
+/// `bound_enabled` is whether the referral the buyer is bound to can still be credited,
+/// when that account was passed along the purchase.
+pub fn referral_binding(
+    mode: StickyReferral,
+    bound_referral: Option<Pubkey>,
+    used: Pubkey,
+    bound_enabled: Option<bool>,
+) -> Result<Option<Pubkey>> {
+    if mode == StickyReferral::Off {
+        return Ok(None);
+    }
+
+    match (bound_referral, bound_enabled) {
+        (None, _) => Ok(Some(used)),
+        (Some(bound_referral), _) if bound_referral == used => Ok(None),
+        // the bound referral was closed or deactivated, the buyer is free to use another one
+        (Some(_), Some(false)) => Ok(Some(used)),
+        (Some(_), _) => err!(BidErrorCode::ReferralAlreadyBound),
+    }
+}
+
+/// Registry entry making a code globally unique, seeded by `ref_code_seed`
+#[account]
+#[derive(InitSpace)]
+pub struct ReferralCode {
+    #[max_len(MAX_REF_CODE_LEN)]
+    pub ref_code: String,
+    pub referral_account: Pubkey,
+    pub created_at: i64,
+}
+
+/// Verifies `referral_account` is the one registered for `ref_code`,
+/// `referral_code` being the registry PDA of the normalized code
+pub fn verify_registered_referral(
+    referral_code: &Account<ReferralCode>,
+    ref_code: &str,
+    referral_account: Pubkey,
+) -> Result<()> {
+    let (registry, _) =
+        Pubkey::find_program_address(&[REFERRAL_CODE_SEED, &ref_code_seed(ref_code)], &crate::ID);
+    require_keys_eq!(
+        referral_code.key(),
+        registry,
+        BidErrorCode::IncorrectReferralData
+    );
+    require_keys_eq!(
+        referral_code.referral_account,
+        referral_account,
+        BidErrorCode::IncorrectReferralData
+    );
+
+    Ok(())
+}
+
+/// Fields of a referral account the tier verification reads
+#[derive(Clone, Copy)]
+pub struct ReferralTierAccount {
+    pub key: Pubkey,
+    pub owner: Pubkey,
+    pub referred_by: Option<Pubkey>,
+    pub active: bool,
+}
+
+impl ReferralTierAccount {
+    pub fn new(key: Pubkey, referral_data: &ReferralAccount) -> Self {
+        Self {
+            key,
+            owner: referral_data.owner,
+            referred_by: referral_data.referred_by,
+            active: referral_enabled(referral_data),
+        }
+    }
+
+    /// Tier read from the account passed at its address.
+    /// A closed referral account is still passed, and reads as an inactive tier.
+    pub fn load(account: &AccountInfo) -> Result<Self> {
+        if account.owner != &crate::ID || account.data_is_empty() {
+            return Ok(Self {
+                key: account.key(),
+                owner: Pubkey::default(),
+                referred_by: None,
+                active: false,
+            });
+        }
+
+        let referral_data = ReferralAccount::try_deserialize(&mut &account.try_borrow_data()?[..])?;
+        Ok(Self::new(account.key(), &referral_data))
+    }
+}
+
+/// Verifies the upper tier passed along a purchase is the `referred_by` link of the tier below
+/// and isn't owned by the buyer, and returns whether it should be credited. The tier has to be
+/// passed whenever the tier below links to it; one with no share, closed or deactivated is not credited.
+pub fn verify_referral_tier(
+    referred_by: Option<Pubkey>,
+    tier_account: Option<&ReferralTierAccount>,
+    buyer: Pubkey,
+    share: u64,
+) -> Result<bool> {
+    match (referred_by, tier_account) {
+        (None, None) => Ok(false),
+        (Some(referrer), Some(tier_account)) => {
+            require_keys_eq!(
+                referrer,
+                tier_account.key,
+                BidErrorCode::IncorrectReferralData
+            );
+            require_keys_neq!(
+                tier_account.owner,
+                buyer,
+                BidErrorCode::IncorrectReferralData
+            );
+            Ok(share > 0 && tier_account.active)
+        }
+        (Some(_), None) | (None, Some(_)) => err!(BidErrorCode::IncorrectReferralData),
+    }
+}
+
+/// Direct referral results of a referral account in one round,
+/// seeded by [REFERRAL_GAME_STATS_SEED, referral_account, game_id]
+#[account]
+#[derive(InitSpace)]
+pub struct ReferralGameStats {
+    pub referral_account: Pubkey,
+    pub game_id: u64,
+    // buyers whose first purchase of the round used the code
+    pub referred_buyers: u64,
+    pub keys: u64,
+    pub volume: u64,
+    pub bonus_earned: u64,
+}
+
+/// Records a referral purchase in the stats of the round, initializing them on the first one
+pub fn record_referral_game_stats(
+    stats: &mut ReferralGameStats,
+    referral_account: Pubkey,
+    game_id: u64,
+    new_buyer: bool,
+    keys: u64,
+    volume: u64,
+    bonus: u64,
+) -> Result<()> {
+    if stats.referral_account == Pubkey::default() {
+        stats.referral_account = referral_account;
+        stats.game_id = game_id;
+    }
+    require!(
+        stats.referral_account == referral_account && stats.game_id == game_id,
+        BidErrorCode::IncorrectReferralData
+    );
+
+    if new_buyer {
+        stats.referred_buyers = stats.referred_buyers.safe_add(1)?;
+    }
+    stats.keys = stats.keys.safe_add(keys)?;
+    stats.volume = stats.volume.safe_add(volume)?;
+    stats.bonus_earned = stats.bonus_earned.safe_add(bonus)?;
+
+    Ok(())
+}
+
+/// Bonus of each tier for a purchase, after verifying the upper tiers passed along it.
+/// Every tier passed is verified against the one below, whether it earns a share or not.
+/// The chain stops at the first inactive tier: the tier above it isn't credited either.
+#[allow(clippy::too_many_arguments)]
+pub fn referral_tier_amounts(
+    referral_data: &ReferralTierAccount,
+    second_tier_data: Option<&ReferralTierAccount>,
+    third_tier_data: Option<&ReferralTierAccount>,
+    buyer: Pubkey,
+    referral_amount: u64,
+    second_tier_share: u64,
+    third_tier_share: u64,
+    divider: u64,
+) -> Result<[u64; REFERRAL_TIERS]> {
+    require_keys_neq!(
+        referral_data.owner,
+        buyer,
+        BidErrorCode::IncorrectReferralData
+    );
+
+    let mut referral_amounts = [referral_amount, 0, 0];
+    if verify_referral_tier(
+        referral_data.referred_by,
+        second_tier_data,
+        buyer,
+        second_tier_share,
+    )? {
+        referral_amounts[1] = mul_div(referral_amount, second_tier_share, divider)?;
+    }
+    let second_tier_active =
+        second_tier_data.is_some_and(|second_tier_data| second_tier_data.active);
+    // Without the second tier there is no link to verify a third tier against
+    if verify_referral_tier(
+        second_tier_data.and_then(|second_tier_data| second_tier_data.referred_by),
+        third_tier_data,
+        buyer,
+        third_tier_share,
+    )? && second_tier_active
+    {
+        referral_amounts[2] = mul_div(referral_amount, third_tier_share, divider)?;
+    }
+
+    Ok(referral_amounts)
+}
+
+/// Credits each tier's bonus to its referral account and to the game totals,
+/// and reserves it in the vault ledger until it is claimed
+pub fn credit_referral_tiers(
+    game: &mut Game,
+    vault_ledger: &mut VaultLedger,
+    referral_data: &mut ReferralAccount,
+    second_tier_data: Option<&AccountInfo>,
+    third_tier_data: Option<&AccountInfo>,
+    referral_amounts: [u64; REFERRAL_TIERS],
+) -> Result<()> {
+    referral_data.total_earned = referral_data.total_earned.safe_add(referral_amounts[0])?;
+    referral_data.tier_earned[0] = referral_data.tier_earned[0].safe_add(referral_amounts[0])?;
+    // Only a credited tier is sure to be an open referral account
+    if let Some(second_tier_data) = second_tier_data.filter(|_| referral_amounts[1] > 0) {
+        credit_referral_tier(second_tier_data, 1, referral_amounts[1])?;
+    }
+    if let Some(third_tier_data) = third_tier_data.filter(|_| referral_amounts[2] > 0) {
+        credit_referral_tier(third_tier_data, 2, referral_amounts[2])?;
+    }
+
+    for (tier, referral_amount) in referral_amounts.into_iter().enumerate() {
+        game.referral_earned = game.referral_earned.safe_add(referral_amount)?;
+        game.referral_tier_earned[tier] = game.referral_tier_earned[tier].safe_add(referral_amount)?;
+        vault_ledger.referral_reserved = vault_ledger.referral_reserved.safe_add(referral_amount)?;
+    }
+
+    Ok(())
+}
+
+/// Credits an upper tier's bonus to the referral account passed for it
+fn credit_referral_tier(account: &AccountInfo, tier: usize, amount: u64) -> Result<()> {
+    let mut tier_data = ReferralAccount::try_deserialize(&mut &account.try_borrow_data()?[..])?;
+    tier_data.total_earned = tier_data.total_earned.safe_add(amount)?;
+    tier_data.tier_earned[tier] = tier_data.tier_earned[tier].safe_add(amount)?;
+
+    tier_data.try_serialize(&mut &mut account.try_borrow_mut_data()?[..])
+}
+
+#[event]
+pub struct ReferralTierUpgradedEvent {
+    pub referrer: Pubkey,
+    pub ref_code: String,
+    pub tier: u8,
+    pub share: u64,
+    pub referred_volume: u64,
+    pub timestamp: i64,
+}
+
+#[derive(Accounts)]
+pub struct UpdateReferralAccount<'info> {
+    #[account(mut)]
+    pub referral_account: Account<'info, ReferralAccount>,
+
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AdminUpdateReferralAccount<'info> {
+    #[account(mut)]
+    pub referral_account: Account<'info, ReferralAccount>,
+
+    pub ownership: Account<'info, Ownership>,
+
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CloseReferralAccount<'info> {
+    #[account(mut, close = owner)]
+    pub referral_account: Account<'info, ReferralAccount>,
+
+    #[account(mut, close = owner)]
+    pub referral_code: Account<'info, ReferralCode>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ViewReferralGameStats<'info> {
+    pub referral_game_stats: Account<'info, ReferralGameStats>,
+}
+
+#[event]
+pub struct ReferralAccountUpdatedEvent {
+    pub referral_account: Pubkey,
+    pub owner: Pubkey,
+    pub payout_wallet: Option<Pubkey>,
+    pub active: bool,
+    pub admin_disabled: bool,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct ReferralAccountClosedEvent {
+    pub owner: Pubkey,
+    pub ref_code: String,
+    pub timestamp: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anchor_lang::solana_program::native_token::LAMPORTS_PER_SOL;
+
+    /// The program's tiers: 20% below 100 SOL, 25% up to 500 SOL, 30% above
+    const VOLUME_TIERS: [ReferralVolumeTier; 3] = [
+        ReferralVolumeTier {
+            min_volume: 0,
+            share: 2000,
+        },
+        ReferralVolumeTier {
+            min_volume: 100 * LAMPORTS_PER_SOL,
+            share: 2500,
+        },
+        ReferralVolumeTier {
+            min_volume: 500 * LAMPORTS_PER_SOL,
+            share: 3000,
+        },
+    ];
+
+    #[test]
+    fn volume_tier_starts_at_its_minimum_volume() {
+        assert_eq!(referral_volume_tier(&VOLUME_TIERS, 0), 0);
+        assert_eq!(
+            referral_volume_tier(&VOLUME_TIERS, 100 * LAMPORTS_PER_SOL - 1),
+            0
+        );
+        assert_eq!(
+            referral_volume_tier(&VOLUME_TIERS, 100 * LAMPORTS_PER_SOL),
+            1
+        );
+        assert_eq!(
+            referral_volume_tier(&VOLUME_TIERS, 500 * LAMPORTS_PER_SOL - 1),
+            1
+        );
+        assert_eq!(
+            referral_volume_tier(&VOLUME_TIERS, 500 * LAMPORTS_PER_SOL),
+            2
+        );
+        assert_eq!(referral_volume_tier(&VOLUME_TIERS, u64::MAX), 2);
+    }
+
+    #[test]
+    fn referrer_only_moves_up_a_tier() {
+        assert_eq!(
+            referral_tier_upgrade(&VOLUME_TIERS, 0, 100 * LAMPORTS_PER_SOL - 1),
+            None
+        );
+        assert_eq!(
+            referral_tier_upgrade(&VOLUME_TIERS, 0, 100 * LAMPORTS_PER_SOL),
+            Some(1)
+        );
+        // a purchase crossing both thresholds skips a tier
+        assert_eq!(
+            referral_tier_upgrade(&VOLUME_TIERS, 0, 600 * LAMPORTS_PER_SOL),
+            Some(2)
+        );
+        // staying in the tier reached is not an upgrade
+        assert_eq!(
+            referral_tier_upgrade(&VOLUME_TIERS, 1, 200 * LAMPORTS_PER_SOL),
+            None
+        );
+        assert_eq!(referral_tier_upgrade(&VOLUME_TIERS, 2, 0), None);
+    }
+
+    #[test]
+    fn rebate_is_taken_from_its_source_only() {
+        assert_eq!(
+            referral_rebate(1_000, 4_000, 1_000, RebateSource::ReferralShare, 10_000).unwrap(),
+            ReferralRebate {
+                referral_amount: 900,
+                treasury_amount: 4_000,
+                rebate_amount: 100,
+            }
+        );
+        assert_eq!(
+            referral_rebate(1_000, 4_000, 1_000, RebateSource::TreasuryFee, 10_000).unwrap(),
+            ReferralRebate {
+                referral_amount: 1_000,
+                treasury_amount: 3_600,
+                rebate_amount: 400,
+            }
+        );
+        // a full rebate leaves nothing on its side, a share above it can't be taken
+        assert_eq!(
+            referral_rebate(1_000, 4_000, 10_000, RebateSource::ReferralShare, 10_000)
+                .unwrap()
+                .referral_amount,
+            0
+        );
+        assert!(referral_rebate(1_000, 40_000, 10_001, RebateSource::TreasuryFee, 10_000).is_err());
+    }
+
+    #[test]
+    fn ref_codes_are_case_insensitive() {
+        assert_eq!(normalize_ref_code("LastBid_Fan-1"), "lastbid_fan-1");
+        assert_eq!(ref_code_seed("ALICE"), ref_code_seed("alice"));
+        assert_ne!(ref_code_seed("alice"), ref_code_seed("alice2"));
+        // the uppercase form is only valid once normalized
+        assert!(validate_ref_code("Alice").is_err());
+        assert!(validate_ref_code(&normalize_ref_code("Alice")).is_ok());
+    }
+
+    #[test]
+    fn ref_code_length_is_bounded() {
+        assert_eq!(
+            validate_ref_code("ab"),
+            Err(BidErrorCode::ReferralCodeTooShort.into())
+        );
+        assert!(validate_ref_code("abc").is_ok());
+        assert!(validate_ref_code(&"a".repeat(MAX_REF_CODE_LEN)).is_ok());
+        assert_eq!(
+            validate_ref_code(&"a".repeat(MAX_REF_CODE_LEN + 1)),
+            Err(BidErrorCode::ReferralCodeTooLong.into())
+        );
+    }
+
+    #[test]
+    fn ref_code_characters_are_restricted() {
+        assert!(validate_ref_code("a-b_c9").is_ok());
+        for ref_code in ["a b c", "abc!", "abc.def", "ab/c", "caf\u{e9}"] {
+            assert_eq!(
+                validate_ref_code(ref_code),
+                Err(BidErrorCode::ReferralCodeInvalidCharacter.into())
+            );
+        }
+    }
+
+    #[test]
+    fn reserved_ref_codes_are_rejected() {
+        for ref_code in RESERVED_REF_CODES {
+            assert_eq!(
+                validate_ref_code(&normalize_ref_code(&ref_code.to_ascii_uppercase())),
+                Err(BidErrorCode::ReferralCodeReserved.into())
+            );
+        }
+        assert!(validate_ref_code("admins").is_ok());
+    }
+
+    fn empty_stats() -> ReferralGameStats {
+        ReferralGameStats {
+            referral_account: Pubkey::default(),
+            game_id: 0,
+            referred_buyers: 0,
+            keys: 0,
+            volume: 0,
+            bonus_earned: 0,
+        }
+    }
+
+    #[test]
+    fn game_stats_add_up_the_referral_purchases_of_a_round() {
+        let referral_account = Pubkey::new_unique();
+        let mut stats = empty_stats();
+
+        record_referral_game_stats(&mut stats, referral_account, 7, true, 3, 3_000, 600).unwrap();
+        record_referral_game_stats(&mut stats, referral_account, 7, false, 2, 2_500, 500).unwrap();
+        record_referral_game_stats(&mut stats, referral_account, 7, true, 1, 1_000, 200).unwrap();
+
+        assert_eq!(stats.referral_account, referral_account);
+        assert_eq!(stats.game_id, 7);
+        // a returning buyer isn't counted twice
+        assert_eq!(stats.referred_buyers, 2);
+        assert_eq!(stats.keys, 6);
+        assert_eq!(stats.volume, 6_500);
+        assert_eq!(stats.bonus_earned, 1_300);
+    }
+
+    #[test]
+    fn game_stats_of_another_referral_or_round_are_rejected() {
+        let referral_account = Pubkey::new_unique();
+        let mut stats = empty_stats();
+        record_referral_game_stats(&mut stats, referral_account, 7, true, 1, 1_000, 200).unwrap();
+
+        assert!(record_referral_game_stats(
+            &mut stats,
+            Pubkey::new_unique(),
+            7,
+            true,
+            1,
+            1_000,
+            200
+        )
+        .is_err());
+        assert!(
+            record_referral_game_stats(&mut stats, referral_account, 8, true, 1, 1_000, 200)
+                .is_err()
+        );
+        assert_eq!(stats.keys, 1);
+    }
+
+    #[test]
+    fn first_referral_used_is_bound() {
+        let used = Pubkey::new_unique();
+
+        assert_eq!(
+            referral_binding(StickyReferral::Round, None, used, None).unwrap(),
+            Some(used)
+        );
+        assert_eq!(
+            referral_binding(StickyReferral::Off, None, used, None).unwrap(),
+            None
+        );
+        // using the bound referral again keeps the binding
+        assert_eq!(
+            referral_binding(StickyReferral::Permanent, Some(used), used, None).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn another_referral_is_rejected_while_the_bound_one_is_enabled() {
+        let bound = Pubkey::new_unique();
+        let used = Pubkey::new_unique();
+
+        assert!(referral_binding(StickyReferral::Permanent, Some(bound), used, None).is_err());
+        assert!(
+            referral_binding(StickyReferral::Permanent, Some(bound), used, Some(true)).is_err()
+        );
+    }
+
+    #[test]
+    fn inactive_or_closed_bound_referral_is_rebound() {
+        let bound = Pubkey::new_unique();
+        let used = Pubkey::new_unique();
+
+        assert_eq!(
+            referral_binding(StickyReferral::Permanent, Some(bound), used, Some(false)).unwrap(),
+            Some(used)
+        );
+    }
+
+    #[test]
+    fn round_binding_ends_with_the_round() {
+        let bound = Pubkey::new_unique();
+
+        assert_eq!(
+            bound_referral(StickyReferral::Round, Some(bound), 1, 1),
+            Some(bound)
+        );
+        assert_eq!(
+            bound_referral(StickyReferral::Round, Some(bound), 1, 2),
+            None
+        );
+        assert_eq!(
+            bound_referral(StickyReferral::Permanent, Some(bound), 1, 2),
+            Some(bound)
+        );
+        assert_eq!(bound_referral(StickyReferral::Off, Some(bound), 1, 1), None);
+    }
+
+    #[test]
+    fn closed_bound_referral_is_not_enabled() {
+        let bound = Pubkey::new_unique();
+        let mut lamports = 0;
+        let mut data = vec![];
+        let system_program = Pubkey::default();
+        let account = AccountInfo::new(
+            &bound,
+            false,
+            false,
+            &mut lamports,
+            &mut data,
+            &system_program,
+            false,
+            0,
+        );
+
+        assert!(!bound_referral_enabled(&account, bound).unwrap());
+        assert!(bound_referral_enabled(&account, Pubkey::new_unique()).is_err());
+    }
+
+    fn tier(referred_by: Option<Pubkey>) -> ReferralTierAccount {
+        ReferralTierAccount {
+            key: Pubkey::new_unique(),
+            owner: Pubkey::new_unique(),
+            referred_by,
+            active: true,
+        }
+    }
+
+    /// Direct referral, its referrer and the one above
+    fn chain() -> [ReferralTierAccount; REFERRAL_TIERS] {
+        let third = tier(None);
+        let second = tier(Some(third.key));
+        let direct = tier(Some(second.key));
+        [direct, second, third]
+    }
+
+    #[test]
+    fn every_tier_of_the_chain_is_credited() {
+        let [direct, second, third] = chain();
+        let amounts = referral_tier_amounts(
+            &direct,
+            Some(&second),
+            Some(&third),
+            Pubkey::new_unique(),
+            1_000,
+            2_500,
+            1_000,
+            10_000,
+        )
+        .unwrap();
+
+        assert_eq!(amounts, [1_000, 250, 100]);
+    }
+
+    #[test]
+    fn tier_owned_by_the_buyer_is_rejected() {
+        let [direct, second, third] = chain();
+        for owned in 0..REFERRAL_TIERS {
+            let tiers = [direct, second, third];
+            let buyer = tiers[owned].owner;
+            assert!(referral_tier_amounts(
+                &tiers[0],
+                Some(&tiers[1]),
+                Some(&tiers[2]),
+                buyer,
+                1_000,
+                2_500,
+                1_000,
+                10_000,
+            )
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn third_tier_is_verified_even_without_a_share() {
+        let [direct, second, _] = chain();
+        let unrelated = tier(None);
+
+        assert!(referral_tier_amounts(
+            &direct,
+            Some(&second),
+            Some(&unrelated),
+            Pubkey::new_unique(),
+            1_000,
+            2_500,
+            0,
+            10_000,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn third_tier_needs_the_second_tier() {
+        let [direct, _, third] = chain();
+
+        assert!(referral_tier_amounts(
+            &direct,
+            None,
+            Some(&third),
+            Pubkey::new_unique(),
+            1_000,
+            2_500,
+            1_000,
+            10_000,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn inactive_or_closed_tiers_are_not_credited() {
+        let [direct, mut second, third] = chain();
+        second.active = false;
+        let buyer = Pubkey::new_unique();
+
+        let amounts = referral_tier_amounts(
+            &direct,
+            Some(&second),
+            Some(&third),
+            buyer,
+            1_000,
+            2_500,
+            1_000,
+            10_000,
+        )
+        .unwrap();
+        assert_eq!(amounts, [1_000, 0, 0]);
+    }
+
+    #[test]
+    fn upper_tiers_share_the_referral_amount_left_by_the_rebate() {
+        let [direct, second, third] = chain();
+        let buyer = Pubkey::new_unique();
+
+        // taken from the referral share, the rebate lowers every tier's bonus
+        let rebate =
+            referral_rebate(1_000, 4_000, 1_000, RebateSource::ReferralShare, 10_000).unwrap();
+        let amounts = referral_tier_amounts(
+            &direct,
+            Some(&second),
+            Some(&third),
+            buyer,
+            rebate.referral_amount,
+            2_500,
+            1_000,
+            10_000,
+        )
+        .unwrap();
+        assert_eq!(amounts, [900, 225, 90]);
+        // and the upper tiers still fit in the treasury fee left
+        assert!(amounts[1] + amounts[2] <= rebate.treasury_amount);
+
+        // taken from the treasury fee, the bonuses are untouched
+        let rebate =
+            referral_rebate(1_000, 4_000, 1_000, RebateSource::TreasuryFee, 10_000).unwrap();
+        let amounts = referral_tier_amounts(
+            &direct,
+            Some(&second),
+            Some(&third),
+            buyer,
+            rebate.referral_amount,
+            2_500,
+            1_000,
+            10_000,
+        )
+        .unwrap();
+        assert_eq!(amounts, [1_000, 250, 100]);
+        assert_eq!(rebate.treasury_amount - amounts[1] - amounts[2], 3_250);
+    }
+
+    #[test]
+    fn linked_tier_has_to_be_passed() {
+        let [direct, second, _] = chain();
+        let buyer = Pubkey::new_unique();
+
+        assert!(
+            referral_tier_amounts(&direct, None, None, buyer, 1_000, 2_500, 1_000, 10_000).is_err()
+        );
+        assert!(referral_tier_amounts(
+            &direct,
+            Some(&second),
+            None,
+            buyer,
+            1_000,
+            2_500,
+            1_000,
+            10_000
+        )
+        .is_err());
+        // the top of the chain links to nothing
+        let [_, second, third] = chain();
+        assert_eq!(
+            referral_tier_amounts(
+                &second,
+                Some(&third),
+                None,
+                buyer,
+                1_000,
+                2_500,
+                1_000,
+                10_000
+            )
+            .unwrap(),
+            [1_000, 250, 0]
+        );
+    }
+
+    #[test]
+    fn closed_tier_reads_as_inactive() {
+        let key = Pubkey::new_unique();
+        let mut lamports = 0;
+        let mut data = vec![];
+        let system_program = Pubkey::default();
+        let account = AccountInfo::new(
+            &key,
+            false,
+            true,
+            &mut lamports,
+            &mut data,
+            &system_program,
+            false,
+            0,
+        );
+
+        let closed = ReferralTierAccount::load(&account).unwrap();
+        assert_eq!(closed.key, key);
+        assert!(!closed.active);
+        assert_eq!(closed.referred_by, None);
+    }
+}
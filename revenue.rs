@@ -0,0 +1,499 @@
+use anchor_lang::prelude::*;
+
+use crate::{
+    available_vault_balance, distribute_to_all_groups, mul_div, BidErrorCode, Game,
+    GroupRevenueCounter, KeyHolderAccount, Ownership, SafeMath, VaultLedger, MAX_HOLDERS,
+    VAULT_LEDGER_SEED,
+};
+
+/// Weight units of a key when revenue is shared evenly, and of the first key of a round when
+/// earlier keys are weighted up. Fine enough for late keys to keep a weight under Decay.
+pub const KEY_WEIGHT_UNIT: u64 = 1_000_000;
+/// Scale of the revenue per weight unit recorded in the group revenue counters
+pub const REVENUE_PRECISION: u64 = 1_000_000_000;
+
+/// How the key holders share of each purchase is split between the keys already bought
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum RevenueWeighting {
+    // every key earns the same
+    Flat,
+    // the weight of the n-th key of the round is KEY_WEIGHT_UNIT * half_life_keys / (half_life_keys + n),
+    // and at least one unit
+    Decay { half_life_keys: u64 },
+}
+
+/// Weight units earned by `keys` keys bought after `first_key_index` keys of the round
+pub fn purchase_weight(
+    weighting: RevenueWeighting,
+    first_key_index: u64,
+    keys: u64,
+) -> Result<u64> {
+    match weighting {
+        RevenueWeighting::Flat => keys.safe_mul(KEY_WEIGHT_UNIT),
+        RevenueWeighting::Decay { half_life_keys } => {
+            require!(half_life_keys > 0, BidErrorCode::InvalidRevenueWeighting);
+
+            // purchases are capped by the timer to a few keys, so the sum stays short
+            let mut weight = 0u64;
+            for key_index in first_key_index..first_key_index.safe_add(keys)? {
+                let key_weight = mul_div(
+                    KEY_WEIGHT_UNIT,
+                    half_life_keys,
+                    half_life_keys.safe_add(key_index)?,
+                )?;
+                weight = weight.safe_add(std::cmp::max(key_weight, 1))?;
+            }
+            Ok(weight)
+        }
+    }
+}
+
+/// Revenue earned by `weight` weight units from a group revenue counter
+pub fn holder_revenue(group_revenue: u64, weight: u64) -> Result<u64> {
+    mul_div(group_revenue, weight, REVENUE_PRECISION)
+}
+
+/// Adds the revenue weight of a purchase to the buyer's entry, once its keys are added
+pub fn add_holder_weight(
+    key_holders: &mut KeyHolderAccount,
+    holder: Pubkey,
+    weight: u64,
+) -> Result<()> {
+    let entry = key_holders
+        .holders
+        .iter_mut()
+        .find(|entry| entry.holder == holder)
+        .ok_or(BidErrorCode::PayerNotInKeyHolders)?;
+    entry.weight = entry.weight.safe_add(weight)?;
+
+    Ok(())
+}
+
+/// Revenue a holder could claim from a group, before the vault balance is checked
+pub fn pending_revenue(
+    key_holders: &KeyHolderAccount,
+    group_revenue_data: &GroupRevenueCounter,
+    holder: Pubkey,
+) -> Result<PendingRevenue> {
+    let group_revenue = group_revenue_data
+        .group_counter
+        .get(key_holders.group_number as usize)
+        .copied()
+        .unwrap_or(0);
+    let entry = key_holders
+        .holders
+        .iter()
+        .find(|entry| entry.holder == holder)
+        .ok_or(BidErrorCode::PayerNotInKeyHolders)?;
+
+    let amount = entry
+        .total_earned
+        .safe_add(holder_revenue(group_revenue, entry.weight)?)?;
+
+    Ok(PendingRevenue {
+        game_id: key_holders.game_id,
+        group_number: key_holders.group_number,
+        weight: entry.weight,
+        amount,
+    })
+}
+
+/// Whether the revenue of a finished round can no longer be claimed.
+/// `closed_at` is when the next round started, a zero `claim_window` never expires.
+pub fn revenue_claim_expired(closed_at: i64, claim_window: i64, now: i64) -> Result<bool> {
+    Ok(claim_window > 0 && now > closed_at.safe_add(claim_window)?)
+}
+
+/// Whether no holder of a group has revenue left to claim, given each holder's
+/// (total_earned, weight) and the group's revenue counter. Rounding dust left in the counter
+/// that no holder's weight turns into a lamport doesn't count.
+pub fn group_revenue_claimed(
+    group_revenue: u64,
+    holders: impl IntoIterator<Item = (u64, u64)>,
+) -> Result<bool> {
+    for (total_earned, weight) in holders {
+        if total_earned > 0 || holder_revenue(group_revenue, weight)? > 0 {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Whether a key holder account can be closed without losing revenue: its group is empty,
+/// or its round is finished and every holder of the group has claimed, or the claim window is over
+pub fn key_holders_closable(
+    key_holders: &KeyHolderAccount,
+    group_revenue_data: &GroupRevenueCounter,
+    current_game_id: u64,
+    claim_window: i64,
+    now: i64,
+) -> Result<bool> {
+    if key_holders.holders.is_empty() {
+        return Ok(true);
+    }
+
+    let group_revenue = group_revenue_data
+        .group_counter
+        .get(key_holders.group_number as usize)
+        .copied()
+        .unwrap_or(0);
+    let holders = key_holders
+        .holders
+        .iter()
+        .map(|entry| (entry.total_earned, entry.weight));
+
+    Ok(key_holders.game_id != current_game_id
+        && (group_revenue_claimed(group_revenue, holders)?
+            || revenue_claim_expired(group_revenue_data.closed_at, claim_window, now)?))
+}
+
+/// Vault balance a revenue claim can't touch.
+/// Revenue of the current round can't use the reserve of finished rounds.
+pub fn revenue_claim_reserve(vault_ledger: &VaultLedger, current_round: bool) -> Result<u64> {
+    let reserved = vault_ledger
+        .referral_reserved
+        .safe_add(vault_ledger.prize_stream_reserved)?;
+    if current_round {
+        reserved.safe_add(vault_ledger.past_revenue_reserved)
+    } else {
+        Ok(reserved)
+    }
+}
+
+/// Takes a paid revenue claim out of the liabilities of its round
+pub fn debit_revenue_claim(
+    game: &mut Game,
+    vault_ledger: &mut VaultLedger,
+    group_revenue_data: &mut GroupRevenueCounter,
+    current_round: bool,
+    amount: u64,
+) -> Result<()> {
+    if current_round {
+        game.revenue_earned = game.revenue_earned.safe_sub(amount)?;
+    } else {
+        group_revenue_data.outstanding = group_revenue_data.outstanding.safe_sub(amount)?;
+        vault_ledger.past_revenue_reserved = vault_ledger.past_revenue_reserved.safe_sub(amount)?;
+    }
+
+    Ok(())
+}
+
+/// Shares the key holders amount of a purchase between the keys bought before it, and settles
+/// the buyer's group so the keys it's about to get don't earn the revenue of earlier purchases.
+/// Returns the distributed part, the rest is rounding dust.
+pub fn distribute_purchase_revenue(
+    key_holders: &mut KeyHolderAccount,
+    group_revenue_data: &mut GroupRevenueCounter,
+    amount: u64,
+    group_number: u64,
+    total_weight: u64,
+) -> Result<u64> {
+    let distributed = distribute_to_all_groups(group_revenue_data, amount, total_weight)?;
+
+    let group_revenue = group_revenue_data.group_counter[group_number as usize];
+    for holder in key_holders.holders.iter_mut() {
+        holder.total_earned = holder
+            .total_earned
+            .safe_add(holder_revenue(group_revenue, holder.weight)?)?;
+    }
+    group_revenue_data.group_counter[group_number as usize] = 0;
+
+    Ok(distributed)
+}
+
+/// Revenue owed and paid for one key holder group of a round
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct RevenueClaimPosition {
+    pub game_id: u64,
+    pub group_number: u64,
+    pub owed: u64,
+    pub paid: u64,
+}
+
+/// Verifies a revenue position of `claimant` and settles up to `max_amount` of it against the vault.
+/// `pending` is what the caller already owes out of the chest vault in the same instruction,
+/// the SOL transfer itself is left to the caller.
+#[allow(clippy::too_many_arguments)]
+pub fn settle_revenue_claim(
+    game: &mut Game,
+    vault_ledger: &mut VaultLedger,
+    key_holders: &mut KeyHolderAccount,
+    group_revenue_data: &mut GroupRevenueCounter,
+    chest_vault: &AccountInfo,
+    claimant: Pubkey,
+    game_id: u64,
+    group_number: u64,
+    pending: u64,
+    max_amount: u64,
+    claim_window: i64,
+    now: i64,
+) -> Result<RevenueClaimPosition> {
+    // Verify the revenue position belongs to the round
+    require!(
+        key_holders.game_id == game_id && group_revenue_data.game_id == game_id,
+        BidErrorCode::IncorrectGameId
+    );
+    require!(
+        key_holders.group_number == group_number,
+        BidErrorCode::IncorrectGroupNumber
+    );
+    let current_round = game_id == game.game_id;
+
+    if current_round {
+        require!(game.total_holders > 0, BidErrorCode::NoKeyHolders);
+
+        // Verify group number
+        let current_group_number = game.total_holders / (MAX_HOLDERS as u64);
+        require!(
+            (group_number < current_group_number) || !(game.total_holders == game.total_groups.safe_mul(MAX_HOLDERS as u64)?
+                && group_number == current_group_number),
+            BidErrorCode::IncorrectGroupNumber
+        );
+    } else {
+        // Verify group number and the claim window of the finished round
+        require!(
+            (group_number as usize) < group_revenue_data.group_counter.len(),
+            BidErrorCode::IncorrectGroupNumber
+        );
+        require!(
+            !revenue_claim_expired(group_revenue_data.closed_at, claim_window, now)?,
+            BidErrorCode::RevenueClaimExpired
+        );
+    }
+
+    let reserved = revenue_claim_reserve(vault_ledger, current_round)?;
+    let available_amount = available_vault_balance(chest_vault, reserved.safe_add(pending)?)?;
+
+    // Distribute group revenue to each holder based on their keys and settle the claimant's share
+    let mut position = None;
+    let group_revenue = group_revenue_data.group_counter[group_number as usize];
+    for holder in key_holders.holders.iter_mut() {
+        if holder.holder != claimant {
+            let holder_amount = holder_revenue(group_revenue, holder.weight)?;
+            holder.total_earned = holder.total_earned.safe_add(holder_amount)?;
+        } else {
+            let revenue_amount = holder
+                .total_earned
+                .safe_add(holder_revenue(group_revenue, holder.weight)?)?;
+            require!(revenue_amount > 0, BidErrorCode::NoRevenue);
+
+            // Pay what the vault can spare, the rest stays claimable
+            let amount = std::cmp::min(
+                std::cmp::min(revenue_amount, available_amount),
+                max_amount,
+            );
+
+            debit_revenue_claim(
+                game,
+                vault_ledger,
+                group_revenue_data,
+                current_round,
+                amount,
+            )?;
+            holder.total_earned = revenue_amount.safe_sub(amount)?;
+
+            position = Some(RevenueClaimPosition {
+                game_id,
+                group_number,
+                owed: revenue_amount,
+                paid: amount,
+            });
+        }
+    }
+    group_revenue_data.group_counter[group_number as usize] = 0;
+
+    position.ok_or_else(|| error!(BidErrorCode::PayerNotInKeyHolders))
+}
+
+/// Weight a purchase would get at the current point of the round
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct PurchaseWeightQuote {
+    pub game_id: u64,
+    pub number_of_keys: u64,
+    pub weight: u64,
+    pub total_weight: u64,
+}
+
+/// Revenue weight and pending revenue of a holder in a group
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct PendingRevenue {
+    pub game_id: u64,
+    pub group_number: u64,
+    pub weight: u64,
+    pub amount: u64,
+}
+
+#[derive(Accounts)]
+pub struct ViewRevenueWeight<'info> {
+    pub game: Account<'info, Game>,
+}
+
+#[derive(Accounts)]
+pub struct ViewPendingRevenue<'info> {
+    pub key_holders: Account<'info, KeyHolderAccount>,
+
+    pub group_revenue_counter: Account<'info, GroupRevenueCounter>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimAllRevenue<'info> {
+    #[account(mut, address = vault_ledger.game @ BidErrorCode::IncorrectGameId)]
+    pub game: Account<'info, Game>,
+
+    #[account(mut, seeds = [VAULT_LEDGER_SEED], bump)]
+    pub vault_ledger: Account<'info, VaultLedger>,
+
+    /// CHECK: the chest vault recorded in the ledger, only lamports are moved out of it
+    #[account(mut, address = vault_ledger.chest_vault @ BidErrorCode::IncorrectChestVault)]
+    pub chest_vault: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(game_id: u64)]
+pub struct SweepExpiredRevenue<'info> {
+    #[account(
+        mut,
+        address = vault_ledger.game @ BidErrorCode::IncorrectGameId,
+        constraint = game.game_id != game_id @ BidErrorCode::IncorrectGameId,
+    )]
+    pub game: Account<'info, Game>,
+
+    #[account(mut, seeds = [VAULT_LEDGER_SEED], bump)]
+    pub vault_ledger: Account<'info, VaultLedger>,
+
+    #[account(
+        mut,
+        constraint = group_revenue_counter.game_id == game_id @ BidErrorCode::IncorrectGameId,
+    )]
+    pub group_revenue_counter: Account<'info, GroupRevenueCounter>,
+
+    /// CHECK: the chest vault recorded in the ledger, only its lamports and data length are read
+    #[account(address = vault_ledger.chest_vault @ BidErrorCode::IncorrectChestVault)]
+    pub chest_vault: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CloseKeyHolders<'info> {
+    #[account(address = vault_ledger.game @ BidErrorCode::IncorrectGameId)]
+    pub game: Account<'info, Game>,
+
+    #[account(seeds = [VAULT_LEDGER_SEED], bump)]
+    pub vault_ledger: Account<'info, VaultLedger>,
+
+    #[account(mut, close = owner)]
+    pub key_holders: Account<'info, KeyHolderAccount>,
+
+    /// Revenue counter of the round of the key holder account
+    #[account(
+        constraint = group_revenue_counter.game_id == key_holders.game_id @ BidErrorCode::IncorrectGameId,
+    )]
+    pub group_revenue_counter: Account<'info, GroupRevenueCounter>,
+
+    pub ownership: Account<'info, Ownership>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+}
+
+#[event]
+pub struct RevenueBatchClaimedEvent {
+    pub key_holder: Pubkey,
+    pub claimed_revenue: u64,
+    pub shortfall: u64,
+    pub positions: Vec<RevenueClaimPosition>,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct RevenueReinvestedEvent {
+    pub game_id: u64,
+    pub key_holder: Pubkey,
+    pub amount: u64,
+    pub number_of_keys: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct ExpiredRevenueSweptEvent {
+    pub game_id: u64,
+    pub amount: u64,
+    pub timestamp: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn purchase_weight_at_the_key_limits() {
+        let max_keys = u64::MAX / KEY_WEIGHT_UNIT;
+        assert_eq!(
+            purchase_weight(RevenueWeighting::Flat, 0, max_keys).unwrap(),
+            max_keys * KEY_WEIGHT_UNIT
+        );
+        assert!(purchase_weight(RevenueWeighting::Flat, 0, max_keys + 1).is_err());
+
+        let decay = RevenueWeighting::Decay {
+            half_life_keys: 100,
+        };
+        assert!(purchase_weight(decay, u64::MAX, 1).is_err());
+        assert!(purchase_weight(RevenueWeighting::Decay { half_life_keys: 0 }, 0, 1).is_err());
+    }
+
+    #[test]
+    fn decayed_keys_keep_a_weight() {
+        let decay = RevenueWeighting::Decay {
+            half_life_keys: 100,
+        };
+
+        assert_eq!(purchase_weight(decay, 0, 1).unwrap(), KEY_WEIGHT_UNIT);
+        assert_eq!(purchase_weight(decay, 100, 1).unwrap(), KEY_WEIGHT_UNIT / 2);
+        // the 10_000th key still weighs about 1% of the first one
+        assert_eq!(
+            purchase_weight(decay, 9_900, 1).unwrap(),
+            KEY_WEIGHT_UNIT / 100
+        );
+        // and a key far down the round weighs at least one unit
+        assert_eq!(purchase_weight(decay, 1_000_000_000, 3).unwrap(), 3);
+    }
+
+    #[test]
+    fn holder_revenue_is_scaled_back_to_lamports() {
+        // 1 SOL shared over 3 keys
+        let total_weight = 3 * KEY_WEIGHT_UNIT;
+        let group_revenue = mul_div(1_000_000_000, REVENUE_PRECISION, total_weight).unwrap();
+
+        assert_eq!(
+            holder_revenue(group_revenue, KEY_WEIGHT_UNIT).unwrap(),
+            333_333_333
+        );
+        assert!(holder_revenue(group_revenue, total_weight).unwrap() <= 1_000_000_000);
+        assert_eq!(holder_revenue(group_revenue, 0).unwrap(), 0);
+    }
+
+    #[test]
+    fn revenue_claim_expires_after_the_window() {
+        assert!(!revenue_claim_expired(1_000, 100, 1_100).unwrap());
+        assert!(revenue_claim_expired(1_000, 100, 1_101).unwrap());
+        // a zero window never expires
+        assert!(!revenue_claim_expired(1_000, 0, i64::MAX).unwrap());
+        assert!(revenue_claim_expired(i64::MAX, 1, i64::MAX).is_err());
+    }
+
+    #[test]
+    fn group_revenue_is_claimed_once_no_holder_is_owed() {
+        assert!(group_revenue_claimed(0, []).unwrap());
+        assert!(group_revenue_claimed(0, [(0, KEY_WEIGHT_UNIT), (0, 1)]).unwrap());
+        assert!(!group_revenue_claimed(0, [(0, KEY_WEIGHT_UNIT), (1, 1)]).unwrap());
+
+        // a counter worth a lamport to a key is still owed
+        let group_revenue = REVENUE_PRECISION / KEY_WEIGHT_UNIT;
+        assert!(!group_revenue_claimed(group_revenue, [(0, KEY_WEIGHT_UNIT)]).unwrap());
+        // but dust no holder's weight turns into a lamport is not
+        assert!(group_revenue_claimed(group_revenue - 1, [(0, KEY_WEIGHT_UNIT)]).unwrap());
+    }
+}
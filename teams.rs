@@ -0,0 +1,158 @@
+use anchor_lang::prelude::*;
+
+use crate::{mul_div, mul_div_up, GroupRevenueCounter, SafeMath, REVENUE_PRECISION};
+
+pub const TEAM_COUNT: usize = 4;
+
+/// Fee splits of a team, in basis points.
+/// The purchase shares split every buy of the team. When the team wins, the final shares are taken
+/// from the main prize and the last bidder gets the rest.
+#[derive(Clone, Copy)]
+pub struct Team {
+    pub prize_pool_share: u64,
+    pub key_holders_share: u64,
+    pub next_round_share: u64,
+    pub final_key_holders_share: u64,
+    pub final_next_round_share: u64,
+}
+
+impl Team {
+    /// Share of a purchase going to the prize pool and the next round together
+    pub fn pot_share(&self) -> Result<u64> {
+        self.prize_pool_share.safe_add(self.next_round_share)
+    }
+
+    /// Share of the prize pool amount that is set aside for the next round
+    pub fn next_round_amount(&self, prize_pool_amount: u64) -> Result<u64> {
+        let pot_share = self.pot_share()?;
+        if pot_share == 0 {
+            return Ok(0);
+        }
+
+        mul_div(prize_pool_amount, self.next_round_share, pot_share)
+    }
+}
+
+/// Purchase splits of `teams` with `share` of every purchase moved from the prize pool to the
+/// next round, on top of each team's own next round share. Evaluated at compile time, so a share
+/// that doesn't fit every team fails the build.
+pub const fn purchase_teams(teams: [Team; TEAM_COUNT], share: u64) -> [Team; TEAM_COUNT] {
+    assert!(
+        fits_every_prize_pool_share(&teams, share),
+        "NEXT_ROUND_SHARE exceeds the prize pool share of a team"
+    );
+
+    let mut teams = teams;
+    let mut team = 0;
+    while team < TEAM_COUNT {
+        teams[team].prize_pool_share -= share;
+        teams[team].next_round_share += share;
+        team += 1;
+    }
+
+    teams
+}
+
+/// Whether `share` can be taken from the prize pool share of every team
+pub const fn fits_every_prize_pool_share(teams: &[Team], share: u64) -> bool {
+    let mut team = 0;
+    while team < teams.len() {
+        if teams[team].prize_pool_share < share {
+            return false;
+        }
+        team += 1;
+    }
+
+    true
+}
+
+/// Adds `amount` to the revenue counter of every group and returns the distributed part.
+/// The rest, rounding dust or the whole amount if there are no keys, is left to the caller.
+pub fn distribute_to_all_groups(
+    group_revenue_data: &mut GroupRevenueCounter,
+    amount: u64,
+    total_weight: u64,
+) -> Result<u64> {
+    if total_weight == 0 {
+        return Ok(0);
+    }
+
+    let revenue_per_weight = mul_div(amount, REVENUE_PRECISION, total_weight)?;
+    for group_revenue in group_revenue_data.group_counter.iter_mut() {
+        *group_revenue = group_revenue.safe_add(revenue_per_weight)?;
+    }
+
+    // rounded up so the holders' rounded down shares never add up to more than what's recorded
+    mul_div_up(revenue_per_weight, total_weight, REVENUE_PRECISION)
+}
+
+#[event]
+pub struct RoundSeededEvent {
+    pub game_id: u64,
+    pub seed: u64,
+    pub owner_top_up: u64,
+    pub prize_pool: u64,
+    pub timestamp: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_round_amount_of_the_largest_prize_pool() {
+        let team = Team {
+            prize_pool_share: 4_000,
+            key_holders_share: 5_000,
+            next_round_share: 1_000,
+            final_key_holders_share: 0,
+            final_next_round_share: 0,
+        };
+
+        assert_eq!(team.next_round_amount(u64::MAX).unwrap(), u64::MAX / 5);
+        assert_eq!(team.next_round_amount(0).unwrap(), 0);
+    }
+
+    #[test]
+    fn next_round_share_moves_from_every_prize_pool() {
+        let team = |prize_pool_share, next_round_share| Team {
+            prize_pool_share,
+            key_holders_share: 10_000 - prize_pool_share - next_round_share,
+            next_round_share,
+            final_key_holders_share: 0,
+            final_next_round_share: 0,
+        };
+        let teams = purchase_teams(
+            [
+                team(5_000, 0),
+                team(5_000, 500),
+                team(4_000, 1_000),
+                team(7_000, 1_000),
+            ],
+            1_000,
+        );
+
+        assert_eq!(teams[0].prize_pool_share, 4_000);
+        assert_eq!(teams[0].next_round_share, 1_000);
+        assert_eq!(teams[2].prize_pool_share, 3_000);
+        assert_eq!(teams[2].next_round_share, 2_000);
+        // the pot and the key holders keep their share
+        assert_eq!(teams[3].pot_share().unwrap(), 8_000);
+        assert_eq!(teams[3].key_holders_share, 2_000);
+    }
+
+    #[test]
+    fn next_round_share_must_fit_every_team() {
+        let team = |prize_pool_share| Team {
+            prize_pool_share,
+            key_holders_share: 0,
+            next_round_share: 0,
+            final_key_holders_share: 0,
+            final_next_round_share: 0,
+        };
+        let teams = [team(5_000), team(4_000)];
+
+        assert!(fits_every_prize_pool_share(&teams, 4_000));
+        assert!(!fits_every_prize_pool_share(&teams, 4_001));
+    }
+}
@@ -0,0 +1,240 @@
+use anchor_lang::prelude::*;
+
+use crate::{BidErrorCode, Fees, Game, Ownership, SafeMath};
+
+pub const VAULT_LEDGER_SEED: &[u8] = b"vault_ledger";
+
+/// Liabilities of the chest vault that outlive a game round, seeded by [VAULT_LEDGER_SEED]
+#[account]
+#[derive(InitSpace)]
+pub struct VaultLedger {
+    // referral bonus credited and not claimed yet, over every round
+    pub referral_reserved: u64,
+    // key holder revenue of finished rounds not claimed yet
+    pub past_revenue_reserved: u64,
+    // main prizes vesting to their winners and not withdrawn yet
+    pub prize_stream_reserved: u64,
+    // game and chest vault the ledger accounts for, recorded by the first initialize_game
+    pub game: Pubkey,
+    pub chest_vault: Pubkey,
+}
+
+/// Records the game and chest vault of the ledger on the first round, and verifies them afterwards.
+/// Instructions outside the game contexts are bound to them through the ledger.
+pub fn bind_vault_ledger(
+    vault_ledger: &mut VaultLedger,
+    game: Pubkey,
+    chest_vault: Pubkey,
+) -> Result<()> {
+    if vault_ledger.game == Pubkey::default() {
+        vault_ledger.game = game;
+        vault_ledger.chest_vault = chest_vault;
+    }
+    require_keys_eq!(vault_ledger.game, game, BidErrorCode::IncorrectGameId);
+    require_keys_eq!(
+        vault_ledger.chest_vault,
+        chest_vault,
+        BidErrorCode::IncorrectChestVault
+    );
+
+    Ok(())
+}
+
+/// Lamports the vault can pay out without dipping below rent exemption or into `reserved`
+pub fn available_vault_balance(chest_vault: &AccountInfo, reserved: u64) -> Result<u64> {
+    let rent_exempt_balance = Rent::get()?.minimum_balance(chest_vault.data_len());
+
+    Ok(chest_vault
+        .lamports()
+        .saturating_sub(rent_exempt_balance)
+        .saturating_sub(reserved))
+}
+
+/// Liability buckets the chest vault has to cover on top of its rent-exempt balance
+pub struct VaultLiabilities {
+    pub prize_pool: u64,
+    pub revenue: u64,
+    pub referral: u64,
+    pub past_revenue: u64,
+    pub prize_stream: u64,
+    pub airdrop: u64,
+    pub next_round_seed: u64,
+    pub dust: u64,
+    pub rent_exempt: u64,
+}
+
+impl VaultLiabilities {
+    /// Without the game, only the buckets of the vault ledger are counted
+    pub fn new(
+        chest_vault: &AccountInfo,
+        game: Option<&Account<Game>>,
+        vault_ledger: &VaultLedger,
+    ) -> Result<Self> {
+        Ok(Self {
+            prize_pool: game.map_or(0, |game| game.prize_pool_balance),
+            revenue: game.map_or(0, |game| game.revenue_earned),
+            referral: vault_ledger.referral_reserved,
+            past_revenue: vault_ledger.past_revenue_reserved,
+            prize_stream: vault_ledger.prize_stream_reserved,
+            airdrop: game.map_or(0, |game| game.airdrop_pot),
+            next_round_seed: game.map_or(0, |game| game.next_round_seed),
+            dust: game.map_or(0, |game| game.dust_carry),
+            rent_exempt: Rent::get()?.minimum_balance(chest_vault.data_len()),
+        })
+    }
+
+    pub fn total(&self) -> Result<u64> {
+        self.prize_pool
+            .safe_add(self.revenue)?
+            .safe_add(self.referral)?
+            .safe_add(self.past_revenue)?
+            .safe_add(self.prize_stream)?
+            .safe_add(self.airdrop)?
+            .safe_add(self.next_round_seed)?
+            .safe_add(self.dust)?
+            .safe_add(self.rent_exempt)
+    }
+}
+
+/// Lamports a purchase sends to the vault that no liability bucket accounts for
+pub fn fee_split_dust(data: &Fees, referral_amounts: &[u64]) -> Result<u64> {
+    let booked = referral_amounts.iter().try_fold(
+        data.prize_pool_amount.safe_add(data.key_holders_amount)?,
+        |booked, referral_amount| booked.safe_add(*referral_amount),
+    )?;
+
+    data.total_amount
+        .safe_sub(data.treasury_amount)?
+        .safe_sub(booked)
+}
+
+/// Fails the instruction if the chest vault can't cover every recorded liability.
+/// Run at the end of every instruction moving lamports in or out of the vault.
+pub fn assert_vault_solvent(
+    chest_vault: &AccountInfo,
+    game: Option<&Account<Game>>,
+    vault_ledger: &VaultLedger,
+) -> Result<()> {
+    let liabilities = VaultLiabilities::new(chest_vault, game, vault_ledger)?;
+    require!(
+        chest_vault.lamports() >= liabilities.total()?,
+        BidErrorCode::VaultInsolvent
+    );
+
+    Ok(())
+}
+
+/// Lamports the chest vault holds above its liabilities, negative when it can't cover them
+pub fn vault_surplus(
+    chest_vault: &AccountInfo,
+    game: Option<&Account<Game>>,
+    vault_ledger: &VaultLedger,
+) -> Result<i128> {
+    let liabilities = VaultLiabilities::new(chest_vault, game, vault_ledger)?;
+
+    Ok(chest_vault.lamports() as i128 - liabilities.total()? as i128)
+}
+
+/// Fails a claim that leaves the chest vault with less surplus, or more deficit, than
+/// `surplus_before`. Claims pay a liability with the lamports they take out, so an underfunded
+/// vault can still pay part of what is owed as long as its deficit doesn't grow.
+pub fn assert_vault_surplus_kept(
+    chest_vault: &AccountInfo,
+    game: Option<&Account<Game>>,
+    vault_ledger: &VaultLedger,
+    surplus_before: i128,
+) -> Result<()> {
+    require!(
+        vault_surplus(chest_vault, game, vault_ledger)? >= surplus_before,
+        BidErrorCode::VaultInsolvent
+    );
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct InitializeVaultLedger<'info> {
+    #[account(
+        init,
+        payer = owner,
+        space = 8 + VaultLedger::INIT_SPACE,
+        seeds = [VAULT_LEDGER_SEED],
+        bump,
+    )]
+    pub vault_ledger: Account<'info, VaultLedger>,
+
+    pub ownership: Account<'info, Ownership>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct AuditVault<'info> {
+    #[account(address = vault_ledger.game @ BidErrorCode::IncorrectGameId)]
+    pub game: Account<'info, Game>,
+
+    #[account(seeds = [VAULT_LEDGER_SEED], bump)]
+    pub vault_ledger: Account<'info, VaultLedger>,
+
+    /// CHECK: the chest vault recorded in the ledger, only its lamports and data length are read
+    #[account(address = vault_ledger.chest_vault @ BidErrorCode::IncorrectChestVault)]
+    pub chest_vault: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SweepSurplus<'info> {
+    #[account(address = vault_ledger.game @ BidErrorCode::IncorrectGameId)]
+    pub game: Account<'info, Game>,
+
+    pub ownership: Account<'info, Ownership>,
+
+    #[account(seeds = [VAULT_LEDGER_SEED], bump)]
+    pub vault_ledger: Account<'info, VaultLedger>,
+
+    /// CHECK: the chest vault recorded in the ledger, only lamports are moved out of it
+    #[account(mut, address = vault_ledger.chest_vault @ BidErrorCode::IncorrectChestVault)]
+    pub chest_vault: UncheckedAccount<'info>,
+
+    /// CHECK: verified against the treasury of the game
+    #[account(mut)]
+    pub treasury: UncheckedAccount<'info>,
+
+    pub owner: Signer<'info>,
+}
+
+#[event]
+pub struct SurplusSweptEvent {
+    pub treasury: Pubkey,
+    pub amount: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct VaultAuditEvent {
+    pub game_id: u64,
+    pub vault_balance: u64,
+    pub prize_pool: u64,
+    pub revenue: u64,
+    pub referral: u64,
+    pub past_revenue: u64,
+    pub prize_stream: u64,
+    pub airdrop: u64,
+    pub next_round_seed: u64,
+    pub dust: u64,
+    pub rent_exempt: u64,
+    pub surplus: u64,
+    pub deficit: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct ClaimShortfallEvent {
+    pub claimant: Pubkey,
+    pub owed: u64,
+    pub paid: u64,
+    pub shortfall: u64,
+    pub timestamp: i64,
+}